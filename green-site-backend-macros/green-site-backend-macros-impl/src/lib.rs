@@ -12,6 +12,7 @@ extern crate proc_macro;
 #[derive(PartialEq, Eq, Copy, Clone)]
 enum AllowedTypes {
     String,
+    Bool,
     U8,
     U16,
     U32,
@@ -33,6 +34,7 @@ impl FromStr for AllowedTypes {
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
             "String" => Ok(AllowedTypes::String),
+            "bool" => Ok(AllowedTypes::Bool),
             "u8" => Ok(AllowedTypes::U8),
             "u16" => Ok(AllowedTypes::U16),
             "u32" => Ok(AllowedTypes::U32),
@@ -54,6 +56,7 @@ impl ToTokens for AllowedTypes {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         let to_append = match self {
             AllowedTypes::String => quote!(std::string::String),
+            AllowedTypes::Bool => quote!(core::primitive::bool),
             AllowedTypes::U8 => quote!(core::primitive::u8),
             AllowedTypes::U16 => quote!(core::primitive::u16),
             AllowedTypes::U32 => quote!(core::primitive::u32),
@@ -111,38 +114,47 @@ impl EnvVar {
                 field_ident,
             })
         } else {
-            return Err(syn::Error::new(
+            Err(syn::Error::new(
                 var_type_path.span(),
-                format!("Bad environment variable type. Must be a String or integer type."),
-            ));
+                "Bad environment variable type. Must be a String, bool or integer type.",
+            ))
         }
     }
 }
 
-const PARSE_TO_NUM: &'static str = "parse_var_to_num";
-const GET_VAR_STR: &'static str = "get_var";
+const PARSE_TO_NUM: &str = "parse_var_to_num";
+const PARSE_TO_BOOL: &str = "parse_var_to_bool";
+const GET_VAR_STR: &str = "get_var";
+const GET_OPTIONAL_VAR_STR: &str = "get_optional_var";
 
 /// Makes a struct have fields that are environment variables where each new environment variable can be specified
 /// with an ``#[env_var]`` attribute, which takes the environment variable's name, the type to parse it into, and an optional
 /// field name for the environment variable in the struct. If no field name is specified, it will use the lowercased form of the
-/// environment variable as the field name. See example for more info.
+/// environment variable as the field name. Environment variables that may be unset can be specified with an ``#[optional_env_var]``
+/// attribute taking the same arguments, which makes the field an ``Option``. See example for more info.
 ///
 /// Example:
 ///
 /// ```
+/// use green_site_backend_macros::env_vars;
+///
 /// #[env_vars]
 /// #[env_var("MY_FIRST_ENV_VAR", String)] // Accepts ``String`` or any integer type to parse environment variable into.
 /// #[env_var("MY_SECOND_ENV_VAR", u16, second)] // Accepts optional third argument for struct field name. By default, it's the environment variable lowercased.
+/// #[optional_env_var("MY_THIRD_ENV_VAR", bool)] // ``bool`` accepts ``true`` or ``false``.
 /// pub struct MyEnvVars;
 /// ```
 ///
 /// This is equivalent to
 ///
 /// ```
+/// # use std::{env, num::ParseIntError, str::FromStr};
+/// # use green_site_backend_macros::EnvVarParseError;
 /// #[derive(Debug, Clone)]
 /// pub struct MyEnvVars {
 ///     pub my_first_env_var: String,
-///     pub second: u16
+///     pub second: u16,
+///     pub my_third_env_var: Option<bool>
 /// }
 ///
 /// impl MyEnvVars {
@@ -150,6 +162,21 @@ const GET_VAR_STR: &'static str = "get_var";
 ///         env::var(var).map_err(|e| EnvVarParseError::EnvVarError(var, e))
 ///     }
 ///
+///     fn get_optional_var(var: &'static str) -> Result<Option<String>, EnvVarParseError> {
+///         match env::var(var) {
+///             Ok(val) => Ok(Some(val)),
+///             Err(env::VarError::NotPresent) => Ok(None),
+///             Err(e) => Err(EnvVarParseError::EnvVarError(var, e)),
+///         }
+///     }
+///
+///     fn parse_var_to_bool(var: &'static str) -> Result<bool, EnvVarParseError> {
+///        let var_str = Self::get_var(var)?;
+///        var_str
+///            .parse()
+///            .map_err(|e| EnvVarParseError::BoolConversionError(var, var_str, e))
+///     }
+///
 ///     fn parse_var_to_num<T: FromStr<Err = ParseIntError>>(var: &'static str) -> Result<T, EnvVarParseError> {
 ///        let var_str = Self::get_var(var)?;
 ///        var_str
@@ -159,8 +186,9 @@ const GET_VAR_STR: &'static str = "get_var";
 ///
 ///     pub fn new() -> Result<MyEnvVars, EnvVarParseError> {
 ///         Ok(MyEnvVars {
-///             my_first_env_var: get_var("MY_FIRST_ENV_VAR")?,
-///             second: parse_var_to_num("MY_SECOND_ENV_VAR"?,
+///             my_first_env_var: Self::get_var("MY_FIRST_ENV_VAR")?,
+///             second: Self::parse_var_to_num("MY_SECOND_ENV_VAR")?,
+///             my_third_env_var: Self::get_optional_var("MY_THIRD_ENV_VAR")?.map(|_| Self::parse_var_to_bool("MY_THIRD_ENV_VAR")).transpose()?,
 ///         })
 ///     }
 /// }
@@ -174,14 +202,16 @@ pub fn env_vars(args: TokenStream, input_stream: TokenStream) -> TokenStream {
     }
 
     let input_struct = parse_macro_input!(input_stream as ItemStruct);
-    let env_vars_iter = input_struct
-        .attrs
-        .into_iter()
-        .filter(|attr| matches!(attr.style, AttrStyle::Outer) && attr.path.is_ident("env_var"));
+    let env_vars_iter = input_struct.attrs.into_iter().filter(|attr| {
+        matches!(attr.style, AttrStyle::Outer)
+            && (attr.path.is_ident("env_var") || attr.path.is_ident("optional_env_var"))
+    });
     let mut struct_fields = Vec::new();
     let mut struct_field_assignments = Vec::new();
     let get_var_fn = Ident::new(GET_VAR_STR, Span::call_site());
     let parse_to_num_fn = Ident::new(PARSE_TO_NUM, Span::call_site());
+    let parse_to_bool_fn = Ident::new(PARSE_TO_BOOL, Span::call_site());
+    let get_optional_var_fn = Ident::new(GET_OPTIONAL_VAR_STR, Span::call_site());
 
     for env_var in env_vars_iter {
         if let Ok(Meta::List(list)) = env_var.parse_meta() {
@@ -196,12 +226,19 @@ pub fn env_vars(args: TokenStream, input_stream: TokenStream) -> TokenStream {
                 Err(err) => return TokenStream::from(err.to_compile_error()),
             };
 
-            struct_fields.push(quote!(pub #field_ident: #var_type));
+            let is_optional = env_var.path.is_ident("optional_env_var");
+            let get_value = match var_type {
+                AllowedTypes::String => quote!(Self::#get_var_fn(#var_name)),
+                AllowedTypes::Bool => quote!(Self::#parse_to_bool_fn(#var_name)),
+                _ => quote!(Self::#parse_to_num_fn(#var_name)),
+            };
 
-            if var_type == AllowedTypes::String {
-                struct_field_assignments.push(quote!(#field_ident: Self::#get_var_fn(#var_name)?));
+            if is_optional {
+                struct_fields.push(quote!(pub #field_ident: core::option::Option<#var_type>));
+                struct_field_assignments.push(quote!(#field_ident: Self::#get_optional_var_fn(#var_name)?.map(|_| #get_value).transpose()?));
             } else {
-                struct_field_assignments.push(quote!(#field_ident: Self::#parse_to_num_fn(#var_name)?));
+                struct_fields.push(quote!(pub #field_ident: #var_type));
+                struct_field_assignments.push(quote!(#field_ident: #get_value?));
             }
         }
     }
@@ -223,6 +260,23 @@ pub fn env_vars(args: TokenStream, input_stream: TokenStream) -> TokenStream {
                 std::env::var(var).map_err(|e| green_site_backend_macros::EnvVarParseError::EnvVarError(var, e))
             }
  
+            #[allow(dead_code)]
+            fn #get_optional_var_fn(var: &'static core::primitive::str) -> core::result::Result<core::option::Option<std::string::String>, green_site_backend_macros::EnvVarParseError> {
+                match std::env::var(var) {
+                    Ok(val) => Ok(Some(val)),
+                    Err(std::env::VarError::NotPresent) => Ok(None),
+                    Err(e) => Err(green_site_backend_macros::EnvVarParseError::EnvVarError(var, e)),
+                }
+            }
+
+            #[allow(dead_code)]
+            fn #parse_to_bool_fn(var: &'static core::primitive::str) -> core::result::Result<core::primitive::bool, green_site_backend_macros::EnvVarParseError> {
+               let var_str = Self::#get_var_fn(var)?;
+               var_str
+                   .parse()
+                   .map_err(|e| green_site_backend_macros::EnvVarParseError::BoolConversionError(var, var_str, e))
+            }
+
             fn #parse_to_num_fn<T: core::str::FromStr<Err = core::num::ParseIntError>>(var: &'static core::primitive::str) -> core::result::Result<T, green_site_backend_macros::EnvVarParseError> {
                let var_str = Self::#get_var_fn(var)?;
                var_str
@@ -1,4 +1,4 @@
-use std::{env, error::Error, fmt::Display, num::ParseIntError};
+use std::{env, error::Error, fmt::Display, num::ParseIntError, str::ParseBoolError};
 use EnvVarParseError::*;
 
 /// Error type for parsing an environment variable.
@@ -6,6 +6,7 @@ use EnvVarParseError::*;
 pub enum EnvVarParseError {
     EnvVarError(&'static str, env::VarError),
     NumConversionError(&'static str, String, ParseIntError),
+    BoolConversionError(&'static str, String, ParseBoolError),
 }
 
 impl Display for EnvVarParseError {
@@ -21,6 +22,10 @@ impl Display for EnvVarParseError {
                 f,
                 "Couldn't convert env variable '{name}' to a port. Got value <{val}>. Error: {err}"
             ),
+            BoolConversionError(name, val, err) => write!(
+                f,
+                "Couldn't convert env variable '{name}' to a bool. Got value <{val}>. Error: {err}"
+            ),
         }
     }
 }
@@ -30,6 +35,7 @@ impl Error for EnvVarParseError {
         match self {
             EnvVarError(_, err) => Some(err),
             NumConversionError(_, _, err) => Some(err),
+            BoolConversionError(_, _, err) => Some(err),
         }
     }
 }
@@ -0,0 +1,5 @@
+-- Deployments from before migrations already have this table, so it must match its original shape.
+CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY NOT NULL,
+    password TEXT NOT NULL
+);
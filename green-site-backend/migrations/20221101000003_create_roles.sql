@@ -0,0 +1,8 @@
+CREATE TABLE roles (
+    name TEXT PRIMARY KEY NOT NULL
+);
+
+INSERT INTO roles (name) VALUES ('admin'), ('operator'), ('viewer');
+
+-- Every existing user starts as a viewer. The backend promotes ADMIN_ACCOUNT_USERNAME right after this migration.
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer';
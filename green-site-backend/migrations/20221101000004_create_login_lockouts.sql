@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS login_lockouts (
+    username TEXT PRIMARY KEY NOT NULL,
+    failures INTEGER NOT NULL,
+    locked_until INTEGER NOT NULL
+);
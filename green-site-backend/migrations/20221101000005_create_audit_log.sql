@@ -0,0 +1,10 @@
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    timestamp INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    peer_addr TEXT,
+    username TEXT,
+    reason TEXT NOT NULL
+);
+
+CREATE INDEX audit_log_timestamp ON audit_log (timestamp);
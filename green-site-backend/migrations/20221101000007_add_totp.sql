@@ -0,0 +1,11 @@
+ALTER TABLE users ADD COLUMN totp_secret BLOB;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+-- The last time step a code was accepted for, so a code can't be replayed.
+ALTER TABLE users ADD COLUMN totp_last_step INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE mfa_challenges (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    challenge_hash TEXT NOT NULL UNIQUE,
+    username TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
@@ -1,18 +1,138 @@
-use actix_web::web::{self, ServiceConfig};
+use actix_web::{
+    guard,
+    web::{self, ServiceConfig},
+};
+
+use crate::rate_limit::{RateLimit, RateLimitPolicy};
 
 use self::{
-    emails::email_endpoint_config, files::file_endpoint_config, login::login_endpoint_config,
-    solar::solar_endpoint_config,
+    account::account_endpoint_config, emails::email_endpoint_config, files::file_endpoint_config,
+    login::login_endpoint_config, logout::logout_endpoint_config,
+    sessions::session_endpoint_config, solar::solar_endpoint_config, users::user_endpoint_config,
 };
 
+pub(crate) use self::files::FileEndpoints;
+
+mod account;
 mod emails;
 mod files;
 mod login;
+mod logout;
+mod sessions;
 mod solar;
+mod users;
+
+/// Logins aren't wrapped in a ``RateLimit`` since ``LoginLimiter`` already throttles them per IP and username. Email
+/// form submissions and file uploads are matched by POST-only scopes first so only they get the strict limit, leaving
+/// downloads free to resume with Range requests. The files scopes are only registered if ``FILE_ENDPOINTS`` enables any
+/// of its endpoints.
+pub(crate) fn endpoint_config(cfg: &mut ServiceConfig, file_endpoints: FileEndpoints) {
+    if file_endpoints.any() {
+        cfg.service(
+            web::scope("/files")
+                .guard(guard::Post())
+                .wrap(RateLimit::new("file_uploads", RateLimitPolicy::STRICT))
+                .configure(|cfg| file_endpoint_config(cfg, file_endpoints)),
+        )
+        .service(
+            web::scope("/files")
+                .wrap(RateLimit::new("files", RateLimitPolicy::DEFAULT))
+                .configure(|cfg| file_endpoint_config(cfg, file_endpoints)),
+        );
+    }
+
+    cfg.service(
+        web::scope("/account")
+            .wrap(RateLimit::new("account", RateLimitPolicy::DEFAULT))
+            .configure(account_endpoint_config),
+    )
+    .service(
+        web::scope("/emails")
+            .guard(guard::Post())
+            .wrap(RateLimit::new("email_submissions", RateLimitPolicy::STRICT))
+            .configure(email_endpoint_config),
+    )
+    .service(
+        web::scope("/emails")
+            .wrap(RateLimit::new("emails", RateLimitPolicy::DEFAULT))
+            .configure(email_endpoint_config),
+    )
+    .service(web::scope("/login").configure(login_endpoint_config))
+    .service(
+        web::scope("/logout")
+            .wrap(RateLimit::new("logout", RateLimitPolicy::DEFAULT))
+            .configure(logout_endpoint_config),
+    )
+    .service(
+        web::scope("/sessions")
+            .wrap(RateLimit::new("sessions", RateLimitPolicy::DEFAULT))
+            .configure(session_endpoint_config),
+    )
+    .service(
+        web::scope("/solar")
+            .wrap(RateLimit::new("solar", RateLimitPolicy::DEFAULT))
+            .configure(solar_endpoint_config),
+    )
+    .service(
+        web::scope("/users")
+            .wrap(RateLimit::new("users", RateLimitPolicy::DEFAULT))
+            .configure(user_endpoint_config),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{call_service, init_service, TestRequest},
+        App,
+    };
+
+    use super::*;
+    use crate::{env_vars::BackendVars, rate_limit::RateLimitPolicy};
+
+    #[actix_web::test]
+    async fn strict_scope_limits_only_its_method() {
+        let endpoints = FileEndpoints::from_vars(&BackendVars::for_tests()).unwrap();
+        let app = init_service(App::new().configure(|cfg| endpoint_config(cfg, endpoints))).await;
+        let peer = "10.1.0.1:4000".parse().unwrap();
+
+        for _ in 0..RateLimitPolicy::STRICT.capacity {
+            let req = TestRequest::post().uri("/emails").peer_addr(peer);
+            let res = call_service(&app, req.to_request()).await;
+
+            assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let req = TestRequest::post().uri("/emails").peer_addr(peer);
+        let res = call_service(&app, req.to_request()).await;
+
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res
+            .headers()
+            .contains_key(actix_web::http::header::RETRY_AFTER));
+
+        // Reading emails is in the default scope, which still has tokens left.
+        let req = TestRequest::get().uri("/emails").peer_addr(peer);
+        let res = call_service(&app, req.to_request()).await;
+
+        assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // Other peers have their own buckets.
+        let req = TestRequest::post()
+            .uri("/emails")
+            .peer_addr("10.1.0.2:4000".parse().unwrap());
+        let res = call_service(&app, req.to_request()).await;
+
+        assert_ne!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn rate_limited_scopes_need_a_client_address() {
+        let endpoints = FileEndpoints::from_vars(&BackendVars::for_tests()).unwrap();
+        let app = init_service(App::new().configure(|cfg| endpoint_config(cfg, endpoints))).await;
+        let res = call_service(&app, TestRequest::get().uri("/solar").to_request()).await;
 
-pub(crate) fn endpoint_config(cfg: &mut ServiceConfig) {
-    cfg.service(web::scope("/emails").configure(email_endpoint_config))
-        // .service(web::scope("/files").configure(file_endpoint_config))
-        .service(web::scope("/login").configure(login_endpoint_config))
-        .service(web::scope("/solar").configure(solar_endpoint_config));
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    }
 }
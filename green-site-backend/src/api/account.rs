@@ -0,0 +1,346 @@
+use actix_web::{
+    error::InternalError,
+    post,
+    web::{Json, JsonConfig, ServiceConfig},
+    HttpRequest, HttpResponse, Responder,
+};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+
+use crate::{
+    credentials::SharedCredentialBackend,
+    env_vars::BackendVars,
+    error::{self, ErrorResponse, MISSING_APP_DATA},
+    listener,
+    login_limit::LoginLimiter,
+    password::{self, StrengthPolicy},
+    security_log::{self, AuthFailureReason},
+    session,
+    token::authenticated_session,
+    totp,
+    users::{self, Totp},
+};
+
+use super::login::{password_error, too_many_attempts, BUFFER_SPACE, MAX_PASSWORD_LEN};
+
+#[derive(Deserialize)]
+struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+struct TotpCode {
+    code: String,
+}
+
+/// Only ever sent once, when TOTP enrollment starts.
+#[derive(Serialize)]
+struct TotpProvisioning {
+    secret: String,
+    provisioning_uri: String,
+}
+
+/// Checks a TOTP code of the user, throttled the same way as logging in.
+async fn check_totp_code(
+    req: &HttpRequest,
+    pool: &SqlitePool,
+    limiter: &LoginLimiter,
+    username: &str,
+    code: &str,
+) -> Result<(), HttpResponse> {
+    let peer_addr = match listener::client_addr(req) {
+        Some(addr) => addr,
+        None => return Err(listener::unknown_client()),
+    };
+
+    if let Err(retry_after) = limiter.check(&peer_addr, username) {
+        security_log::auth_failure(
+            Some(&peer_addr),
+            Some(username),
+            AuthFailureReason::RateLimited,
+        );
+
+        return Err(too_many_attempts(retry_after));
+    }
+
+    match users::verify_totp(pool, username, code).await {
+        Ok(true) => {
+            limiter.record_success(username).await;
+
+            Ok(())
+        }
+        Ok(false) => {
+            security_log::auth_failure(
+                Some(&peer_addr),
+                Some(username),
+                AuthFailureReason::BadTotpCode,
+            );
+            limiter.record_failure(username).await;
+
+            Err(HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Bad TOTP code.".to_string(),
+            }))
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while verifying TOTP code: {err}");
+
+            Err(error::internal_server_error())
+        }
+    }
+}
+
+macro_rules! verify_pool_limiter {
+    ($req:ident) => {
+        match (
+            $req.app_data::<SqlitePool>(),
+            $req.app_data::<LoginLimiter>(),
+        ) {
+            (Some(pool), Some(limiter)) => (pool, limiter),
+            _ => {
+                error!(
+                    "{MISSING_APP_DATA}. SQLite Connection Pool: {:?}; LoginLimiter: {:?}",
+                    $req.app_data::<SqlitePool>(),
+                    $req.app_data::<LoginLimiter>(),
+                );
+
+                return error::internal_server_error();
+            }
+        }
+    };
+}
+
+macro_rules! verify_session {
+    ($req:ident) => {
+        match authenticated_session(&$req).await {
+            Some(session) => session,
+            None => return HttpResponse::Unauthorized().finish(),
+        }
+    };
+}
+
+/// Changes the caller's password after checking their current one. Every other session of the caller is revoked.
+#[post("/password")]
+async fn change_password(req: HttpRequest, change: Json<PasswordChange>) -> impl Responder {
+    let (pool, limiter) = verify_pool_limiter!(req);
+    let (vars, backend) = match (
+        req.app_data::<BackendVars>(),
+        req.app_data::<SharedCredentialBackend>(),
+    ) {
+        (Some(vars), Some(backend)) => (vars, backend),
+        _ => {
+            error!(
+                "{MISSING_APP_DATA}. BackendVars set: {}; Credential backend set: {}",
+                req.app_data::<BackendVars>().is_some(),
+                req.app_data::<SharedCredentialBackend>().is_some(),
+            );
+
+            return error::internal_server_error();
+        }
+    };
+    let session = verify_session!(req);
+
+    if !backend.stores_passwords() {
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Passwords are managed by the directory server.".to_string(),
+        });
+    }
+
+    if let Some(err) =
+        password_error(&change.current_password).or_else(|| password_error(&change.new_password))
+    {
+        return HttpResponse::BadRequest().json(err);
+    }
+
+    if let Some(err) = StrengthPolicy::new(vars).check(&session.username, &change.new_password) {
+        return HttpResponse::BadRequest().json(ErrorResponse { error: err });
+    }
+
+    let peer_addr = match listener::client_addr(&req) {
+        Some(addr) => addr,
+        None => return listener::unknown_client(),
+    };
+
+    // Guessing the current password here is throttled the same way as logging in.
+    if let Err(retry_after) = limiter.check(&peer_addr, &session.username) {
+        security_log::auth_failure(
+            Some(&peer_addr),
+            Some(&session.username),
+            AuthFailureReason::RateLimited,
+        );
+
+        return too_many_attempts(retry_after);
+    }
+
+    let is_valid = match backend
+        .verify(pool, &session.username, &change.current_password)
+        .await
+    {
+        Ok(is_valid) => is_valid,
+        Err(err) => {
+            error!("Encountered error while checking current password: {err}");
+
+            return error::internal_server_error();
+        }
+    };
+
+    if !is_valid {
+        security_log::auth_failure(
+            Some(&peer_addr),
+            Some(&session.username),
+            AuthFailureReason::BadCredentials,
+        );
+        limiter.record_failure(&session.username).await;
+
+        return HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Bad credentials.".to_string(),
+        });
+    }
+
+    limiter.record_success(&session.username).await;
+
+    let password_hash = match password::hash_password(&change.new_password).await {
+        Ok(hash) => hash,
+        Err(err) => {
+            error!("Couldn't hash new password of {}: {err}", session.username);
+
+            return error::internal_server_error();
+        }
+    };
+
+    if let Err(err) = users::set_password(pool, &session.username, &password_hash).await {
+        error!("Encountered sqlx error while changing password: {err}");
+
+        return error::internal_server_error();
+    }
+
+    let revoked = match session.id {
+        Some(id) => session::revoke_other_user_sessions(pool, &session.username, id).await,
+        None => session::revoke_user_sessions(pool, &session.username).await,
+    };
+
+    match revoked {
+        Ok(revoked) => {
+            info!(
+                "{} changed their password. Revoked {revoked} other sessions",
+                session.username
+            );
+
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while revoking other sessions: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+/// Starts TOTP enrollment with a new secret. TOTP is only required at login once a code is confirmed.
+#[post("/totp")]
+async fn enroll_totp(req: HttpRequest) -> impl Responder {
+    let (pool, _) = verify_pool_limiter!(req);
+    let session = verify_session!(req);
+
+    match users::totp(pool, &session.username).await {
+        Ok(Some(Totp { enabled: true, .. })) => {
+            return HttpResponse::Conflict().json(ErrorResponse {
+                error: "TOTP is already enabled.".to_string(),
+            })
+        }
+        Ok(_) => {}
+        Err(err) => {
+            error!("Encountered sqlx error while looking up TOTP: {err}");
+
+            return error::internal_server_error();
+        }
+    }
+
+    let secret = totp::generate_secret();
+
+    if let Err(err) = users::set_totp_secret(pool, &session.username, Some(&secret)).await {
+        error!("Encountered sqlx error while starting TOTP enrollment: {err}");
+
+        return error::internal_server_error();
+    }
+
+    let (secret, provisioning_uri) = totp::provisioning(&session.username, &secret);
+
+    HttpResponse::Ok().json(TotpProvisioning {
+        secret,
+        provisioning_uri,
+    })
+}
+
+#[post("/totp/confirm")]
+async fn confirm_totp(req: HttpRequest, totp_code: Json<TotpCode>) -> impl Responder {
+    let (pool, limiter) = verify_pool_limiter!(req);
+    let session = verify_session!(req);
+
+    match check_totp_code(&req, pool, limiter, &session.username, &totp_code.code).await {
+        Ok(()) => {
+            info!("{} enabled TOTP", session.username);
+
+            HttpResponse::Ok().finish()
+        }
+        Err(res) => res,
+    }
+}
+
+#[post("/totp/disable")]
+async fn disable_totp(req: HttpRequest, totp_code: Json<TotpCode>) -> impl Responder {
+    let (pool, limiter) = verify_pool_limiter!(req);
+    let session = verify_session!(req);
+
+    match users::totp(pool, &session.username).await {
+        Ok(Some(Totp { enabled: true, .. })) => {}
+        Ok(_) => {
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: "TOTP isn't enabled.".to_string(),
+            })
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while looking up TOTP: {err}");
+
+            return error::internal_server_error();
+        }
+    }
+
+    if let Err(res) = check_totp_code(&req, pool, limiter, &session.username, &totp_code.code).await
+    {
+        return res;
+    }
+
+    match users::set_totp_secret(pool, &session.username, None).await {
+        Ok(()) => {
+            info!("{} disabled TOTP", session.username);
+
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while disabling TOTP: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+pub(crate) fn account_endpoint_config(cfg: &mut ServiceConfig) {
+    let json_cfg = JsonConfig::default()
+        .limit(2 * MAX_PASSWORD_LEN + BUFFER_SPACE)
+        .content_type(|mime_type| mime_type == mime::APPLICATION_JSON)
+        .error_handler(|err, _| {
+            let res = HttpResponse::BadRequest().json(ErrorResponse {
+                error: err.to_string(),
+            });
+
+            InternalError::from_response(err, res).into()
+        });
+
+    cfg.service(change_password)
+        .service(enroll_totp)
+        .service(confirm_totp)
+        .service(disable_totp)
+        .app_data(json_cfg);
+}
@@ -1,4 +1,10 @@
-use std::{error::Error, time::Duration};
+use std::{
+    error::Error,
+    io::{self, BufRead, BufReader, Write},
+    net::TcpStream,
+    sync::Arc,
+    time::Duration,
+};
 
 use actix_web::{
     get, post,
@@ -15,10 +21,13 @@ use lettre::{
     AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
 };
 use log::error;
-use native_tls::TlsConnector;
+use rustls::{ClientConfig, ClientConnection, ServerName, StreamOwned};
 use serde::{Deserialize, Serialize};
 
-use crate::{env_vars::BackendVars, error::internal_server_error, verify_admin_token};
+use crate::{
+    env_vars::BackendVars, error::internal_server_error, role::Permission, tls::TrustedRoots,
+    tls_policy::TlsPolicy, verify_permission,
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Email {
@@ -29,7 +38,7 @@ struct Email {
 }
 
 impl Email {
-    fn to_message(self, vars: &BackendVars) -> Result<Message, lettre::error::Error> {
+    fn into_message(self, vars: &BackendVars) -> Result<Message, lettre::error::Error> {
         Message::builder()
             .from(
                 Mailbox::try_from((self.from_name, self.from_email))
@@ -46,7 +55,7 @@ impl Email {
     }
 }
 
-fn get_two_vars<'a, T: 'static, U: 'static>(req: &'a HttpRequest) -> Option<(&'a T, &'a U)> {
+fn get_two_vars<T: 'static, U: 'static>(req: &HttpRequest) -> Option<(&T, &U)> {
     Some((req.app_data()?, req.app_data()?))
 }
 
@@ -91,17 +100,61 @@ fn parse_headers(headers: String) -> Email {
     }
 }
 
+/// Reads the IMAP greeting and upgrades the connection with ``STARTTLS``. imap only does that itself with native TLS,
+/// so it's done here before the connection is handed to imap over rustls.
+fn imap_starttls(tcp: &TcpStream) -> imap::Result<()> {
+    let mut reader = BufReader::new(tcp);
+    let mut writer = tcp;
+    let mut line = String::new();
+
+    reader.read_line(&mut line)?;
+
+    if !line.starts_with("* OK") {
+        return Err(imap::Error::Bad(line));
+    }
+
+    writer.write_all(b"a0 STARTTLS\r\n")?;
+
+    let status = loop {
+        line.clear();
+
+        if reader.read_line(&mut line)? == 0 {
+            return Err(imap::Error::ConnectionLost);
+        }
+
+        if let Some(status) = line.strip_prefix("a0 ") {
+            break status;
+        }
+    };
+
+    if !status.starts_with("OK") {
+        return Err(imap::Error::No(line));
+    }
+
+    // Anything sent after the reply would otherwise be taken as if it came over TLS.
+    if !reader.buffer().is_empty() {
+        return Err(imap::Error::Bad(
+            "Unexpected data after the STARTTLS reply".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
 #[get("")]
 async fn get_emails(req: HttpRequest) -> impl Responder {
     fn imap_emails(
-        connector: &TlsConnector,
+        config: Arc<ClientConfig>,
         vars: &BackendVars,
     ) -> Result<Vec<Email>, imap::Error> {
-        let conn = imap::connect_starttls(
-            (vars.email_server_ip.as_str(), vars.imap_server_port),
-            vars.email_server_ip.as_str(),
-            connector,
-        )?;
+        let tcp = TcpStream::connect((vars.email_server_ip.as_str(), vars.imap_server_port))?;
+
+        imap_starttls(&tcp)?;
+
+        let server_name = ServerName::try_from(vars.email_server_name())
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        let tls = ClientConnection::new(config, server_name).map_err(io::Error::other)?;
+        let conn = imap::Client::new(StreamOwned::new(tls, tcp));
 
         let mut session = conn
             .login(vars.email_user.as_str(), vars.email_pass.as_str())
@@ -130,12 +183,12 @@ async fn get_emails(req: HttpRequest) -> impl Responder {
         Ok(emails)
     }
 
-    let (vars, connector): (&BackendVars, &TlsConnector) = verify_two_vars!(req);
-    let (vars, connector) = (vars.clone(), connector.clone());
+    let (vars, roots): (&BackendVars, &TrustedRoots) = verify_two_vars!(req);
+    let (vars, config) = (vars.clone(), roots.client_config());
 
-    verify_admin_token!(req, vars);
+    verify_permission!(req, Permission::ReadEmails);
 
-    let emails_task = task::spawn_blocking(move || imap_emails(&connector, &vars));
+    let emails_task = task::spawn_blocking(move || imap_emails(config, &vars));
 
     match emails_task.await {
         Ok(Ok(emails)) => HttpResponse::Ok().json(emails),
@@ -158,11 +211,13 @@ async fn upload_email(req: HttpRequest, email: Json<Email>) -> impl Responder {
         email: Email,
         vars: &BackendVars,
         cert: &Certificate,
+        policy: TlsPolicy,
     ) -> Result<(), Box<dyn Error>> {
-        let tls_params = TlsParameters::builder(vars.email_server_ip.clone())
+        // lettre's rustls client always verifies hostnames, so TLS_ACCEPT_INVALID_HOSTNAMES doesn't apply to SMTP.
+        let tls_params = TlsParameters::builder(vars.email_server_name().to_string())
             .add_root_certificate(cert.clone())
-            .dangerous_accept_invalid_hostnames(true)
-            .build_native()?;
+            .set_min_tls_version(policy.smtp_min_version())
+            .build_rustls()?;
         let transport =
             AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(vars.email_server_ip.clone())
                 .port(vars.smtp_server_port)
@@ -174,14 +229,14 @@ async fn upload_email(req: HttpRequest, email: Json<Email>) -> impl Responder {
                 ))
                 .build();
 
-        transport.send(email.to_message(vars)?).await?;
+        transport.send(email.into_message(vars)?).await?;
 
         Ok(())
     }
 
-    let (vars, cert) = verify_two_vars!(req);
+    let (vars, roots): (&BackendVars, &TrustedRoots) = verify_two_vars!(req);
 
-    match smtp_upload(email.0, vars, cert).await {
+    match smtp_upload(email.0, vars, &roots.smtp_cert(), roots.policy()).await {
         Ok(()) => HttpResponse::Ok().finish(),
         Err(err) => {
             error!("Error encountered uploading email to mail server: {err}");
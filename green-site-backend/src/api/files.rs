@@ -1,60 +1,94 @@
-use std::{error::Error, fmt::Display};
+use std::{
+    error::Error,
+    fmt::Display,
+    future::Future,
+    io::{self, Read, Write},
+    time::SystemTime,
+};
 
 use actix_multipart::{Multipart, MultipartError};
 use actix_web::{
-    get, post,
+    body::SizedStream,
+    get,
+    http::header::{
+        self, ContentDisposition, ContentRange, ContentRangeSpec, DispositionParam,
+        DispositionType, ETag, EntityTag, Header, HttpDate, IfRange, LastModified, Range,
+    },
+    post,
+    rt::task,
     web::{Path, ServiceConfig},
     HttpRequest, HttpResponse, Responder,
 };
-
-use futures::{AsyncRead, AsyncReadExt, StreamExt};
-use log::error;
+use bytes::Bytes;
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    executor, SinkExt, StreamExt,
+};
+use log::{error, warn};
 use rand::Rng;
 use serde::Serialize;
-use suppaftp::{
-    async_native_tls::{Certificate, Protocol, TlsConnector},
-    types::Response,
-    FtpError, FtpResult, FtpStream, Status, TlsConnector as FtpTlsConnector,
-};
+use suppaftp::{types::Response, FtpError, FtpResult, FtpStream, Status, TlsConnector};
 
 use crate::{
     env_vars::BackendVars,
     error::{internal_server_error, ErrorResponse},
-    verify_admin_token,
+    role::Permission,
+    tls::TrustedRoots,
+    verify_permission,
 };
 
-fn get_var_and_ftp_cert(req: &HttpRequest) -> Option<(&BackendVars, &Certificate)> {
+/// Uploads bigger than this are aborted unless ``MAX_UPLOAD_BYTES`` is set.
+const DEFAULT_MAX_UPLOAD_BYTES: u64 = 100 * 1024 * 1024;
+/// How much of a downloaded file is read from the FTP server at a time.
+const DOWNLOAD_CHUNK_SIZE: usize = 64 * 1024;
+/// The longest name an uploaded file may have, in characters.
+const MAX_FILE_NAME_LEN: usize = 72;
+
+/// Whether the name is a plain file name the FTP server can store as is. It must be 1 to 72 characters and can't have
+/// path separators, ``..`` or control characters.
+fn is_valid_file_name(name: &str) -> bool {
+    (1..=MAX_FILE_NAME_LEN).contains(&name.chars().count())
+        && name != "."
+        && !name.contains(['/', '\\'])
+        && !name.contains("..")
+        && !name.chars().any(char::is_control)
+}
+
+fn get_var_and_roots(req: &HttpRequest) -> Option<(&BackendVars, &TrustedRoots)> {
     Some((req.app_data()?, req.app_data()?))
 }
 
-macro_rules! verify_var_cert {
+macro_rules! verify_var_roots {
     ($req:ident) => {
-        match get_var_and_ftp_cert(&$req) {
+        match get_var_and_roots(&$req) {
             Some(pair) => pair,
             None => return crate::error::internal_server_error(),
         }
     };
 }
 
-fn get_tls_connector(cert: &Certificate) -> FtpTlsConnector {
-    TlsConnector::new()
-        .min_protocol_version(Some(Protocol::Tlsv12))
-        .max_protocol_version(Some(Protocol::Tlsv12))
-        .add_root_certificate(cert.clone())
-        .use_sni(false)
-        .danger_accept_invalid_hostnames(true)
-        .into()
+/// Runs blocking FTP commands on the blocking thread pool, since suppaftp only supports rustls with blocking IO. They
+/// start right away, before the returned future is polled.
+fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> FtpResult<T> + Send + 'static,
+) -> impl Future<Output = FtpResult<T>> {
+    let handle = task::spawn_blocking(f);
+
+    async move {
+        handle
+            .await
+            .unwrap_or_else(|err| Err(FtpError::ConnectionError(io::Error::other(err))))
+    }
 }
 
-async fn secure_ftp_login(vars: &BackendVars, tls_cert: &Certificate) -> FtpResult<FtpStream> {
-    let mut ftp_stream = FtpStream::connect((vars.ftps_server_ip.as_str(), vars.ftps_server_port))
-        .await?
-        .into_secure(get_tls_connector(tls_cert), &vars.ftps_server_ip)
-        .await?;
+fn secure_ftp_login(vars: &BackendVars, roots: &TrustedRoots) -> FtpResult<FtpStream> {
+    let mut ftp_stream = FtpStream::connect((vars.ftps_server_ip.as_str(), vars.ftps_server_port))?
+        .into_secure(
+            TlsConnector::from(roots.client_config()),
+            vars.ftps_server_name(),
+        )?;
 
-    ftp_stream
-        .login(vars.ftps_user.as_str(), vars.ftps_pass.as_str())
-        .await?;
+    ftp_stream.login(vars.ftps_user.as_str(), vars.ftps_pass.as_str())?;
 
     Ok(ftp_stream)
 }
@@ -64,6 +98,22 @@ struct File {
     name: String,
     id: String,
     size: u64,
+    #[serde(skip)]
+    modified: SystemTime,
+}
+
+impl File {
+    /// A weak validator derived from the size and modification time in the FTP server's listing. Those can stay the same
+    /// when the contents change, so it's never used to resume a download.
+    fn etag(&self) -> EntityTag {
+        let modified = self
+            .modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        EntityTag::new_weak(format!("{:x}-{modified:x}", self.size))
+    }
 }
 
 fn split_name(name: &str) -> Option<(String, String)> {
@@ -74,15 +124,15 @@ fn split_name(name: &str) -> Option<(String, String)> {
     Some((name[..split].to_string(), name[split + 1..].to_string()))
 }
 
-async fn list_files(var: &BackendVars, cert: &Certificate) -> FtpResult<Vec<File>> {
+fn list_files(var: &BackendVars, roots: &TrustedRoots) -> FtpResult<Vec<File>> {
     use suppaftp::list::File as FtpFile;
 
-    let file_list = secure_ftp_login(var, cert).await?.list(None).await?;
+    let file_list = secure_ftp_login(var, roots)?.list(None)?;
     let mut processed_files = Vec::new();
 
     for file in file_list {
         let ftp_file = FtpFile::try_from(file).map_err(|_| FtpError::BadResponse)?;
-        let (name, id) = match split_name(ftp_file.name()) {
+        let (id, name) = match split_name(ftp_file.name()) {
             Some(pair) => pair,
             None => return Err(FtpError::BadResponse),
         };
@@ -90,6 +140,7 @@ async fn list_files(var: &BackendVars, cert: &Certificate) -> FtpResult<Vec<File
             name,
             id,
             size: ftp_file.size() as u64,
+            modified: ftp_file.modified(),
         };
 
         processed_files.push(processed_file)
@@ -100,11 +151,12 @@ async fn list_files(var: &BackendVars, cert: &Certificate) -> FtpResult<Vec<File
 
 #[get("")]
 async fn get_files(req: HttpRequest) -> impl Responder {
-    let (var, cert) = verify_var_cert!(req);
+    let (var, roots) = verify_var_roots!(req);
+    let (var, roots) = (var.clone(), roots.clone());
 
-    verify_admin_token!(req, var);
+    verify_permission!(req, Permission::ListFiles);
 
-    match list_files(var, cert).await {
+    match run_blocking(move || list_files(&var, &roots)).await {
         Ok(files) => HttpResponse::Ok().json(files),
         Err(err) => {
             error!("Encountered internal error while listing files from FTP server: {err}");
@@ -120,6 +172,7 @@ enum UploadError {
     UpMultipartError(MultipartError),
     NoData,
     BadFileName,
+    TooLarge(u64),
 }
 
 impl Display for UploadError {
@@ -129,6 +182,7 @@ impl Display for UploadError {
             UploadError::UpMultipartError(err) => write!(f, "{err}"),
             UploadError::NoData => write!(f, "No data in multipart"),
             UploadError::BadFileName => write!(f, "Bad file name multipart"),
+            UploadError::TooLarge(max) => write!(f, "Upload is over {max} bytes"),
         }
     }
 }
@@ -147,58 +201,157 @@ impl From<MultipartError> for UploadError {
     }
 }
 
+/// Ends a cut short ``STOR`` and deletes what the FTP server stored of it. The server only stops writing the file once
+/// the data connection is closed, so its reply is read before the ``DELE`` is sent on the same control connection.
+fn abort_upload(conn: &mut FtpStream, data: impl Write, name: &str) -> FtpResult<()> {
+    match conn.finalize_put_stream(data) {
+        // Servers may answer the unfinished transfer with an error, which still ends it.
+        Ok(()) | Err(FtpError::UnexpectedResponse(_)) => conn.rm(name),
+        Err(err) => Err(err),
+    }
+}
+
+fn log_abort(name: &str, result: FtpResult<()>) {
+    match result {
+        Ok(()) => warn!("Deleted partial upload {name} after the upload was aborted"),
+        Err(err) => error!("Couldn't delete partial upload {name}: {err}"),
+    }
+}
+
+/// A message from the upload handler to the thread writing the upload to the FTP server.
+enum UploadChunk {
+    Data(Bytes),
+    End,
+}
+
+/// Stores the chunks as the file ``name`` as they arrive. The file is deleted unless the chunks end with
+/// ``UploadChunk::End`` and the FTP server confirms it was stored, so an upload the handler gave up on, or that was
+/// dropped because the client disconnected, doesn't leave a partial file behind.
+fn store_upload(
+    vars: &BackendVars,
+    roots: &TrustedRoots,
+    name: &str,
+    mut chunks: Receiver<UploadChunk>,
+) -> FtpResult<()> {
+    let mut conn = secure_ftp_login(vars, roots)?;
+    let mut data = conn.put_with_stream(name)?;
+
+    loop {
+        let written = match executor::block_on(chunks.next()) {
+            Some(UploadChunk::Data(bytes)) => data.write_all(&bytes),
+            Some(UploadChunk::End) => break,
+            None => Err(io::ErrorKind::ConnectionAborted.into()),
+        };
+
+        if let Err(err) = written {
+            log_abort(name, abort_upload(&mut conn, data, name));
+
+            return Err(FtpError::ConnectionError(err));
+        }
+    }
+
+    match conn.finalize_put_stream(data) {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            if let Err(rm_err) = conn.rm(name) {
+                error!("Couldn't delete partial upload {name}: {rm_err}");
+            }
+
+            Err(err)
+        }
+    }
+}
+
 #[post("")]
 async fn upload_file(req: HttpRequest, multi_part: Multipart) -> impl Responder {
     use UploadError::*;
 
+    /// Streams the first multipart field into the FTP server, reading at most one chunk from the client ahead of what
+    /// was written.
     async fn ftp_upload(
         vars: &BackendVars,
-        cert: &Certificate,
+        roots: &TrustedRoots,
         mut file: Multipart,
     ) -> Result<(), UploadError> {
+        let max_bytes = vars.max_upload_bytes.unwrap_or(DEFAULT_MAX_UPLOAD_BYTES);
         let mut field = file.next().await.ok_or(NoData)??;
-        let mut bytes_vec = Vec::new();
-
-        loop {
-            if let Some(bytes_res) = field.next().await {
-                bytes_vec.extend(bytes_res?);
-            } else {
-                break;
+        let file_name = field
+            .content_disposition()
+            .get_filename()
+            .filter(|name| is_valid_file_name(name))
+            .ok_or(BadFileName)?
+            .to_string();
+        // Waits for data before connecting so empty uploads never reach the FTP server.
+        let mut chunk = loop {
+            match field.next().await {
+                Some(bytes) => {
+                    let bytes = bytes?;
+
+                    if !bytes.is_empty() {
+                        break bytes;
+                    }
+                }
+                None => return Err(NoData),
+            }
+        };
+        let rand_id = rand::thread_rng().gen::<u128>();
+        let name = format!("{rand_id}-{file_name}");
+        let (mut chunks, receiver) = mpsc::channel(0);
+        let (vars, roots) = (vars.clone(), roots.clone());
+        let stored = run_blocking(move || store_upload(&vars, &roots, &name, receiver));
+        let mut written = 0;
+        let streamed = async {
+            loop {
+                written += chunk.len() as u64;
+
+                if written > max_bytes {
+                    return Err(TooLarge(max_bytes));
+                }
+
+                // The FTP thread only hangs up when it failed, which it returns below.
+                if chunks.send(UploadChunk::Data(chunk)).await.is_err() {
+                    return Ok(());
+                }
+
+                chunk = match field.next().await {
+                    Some(bytes) => bytes?,
+                    None => {
+                        let _ = chunks.send(UploadChunk::End).await;
+
+                        return Ok(());
+                    }
+                };
             }
         }
+        .await;
 
-        if bytes_vec.is_empty() {
-            return Err(NoData);
-        }
+        drop(chunks);
 
-        let mut conn = secure_ftp_login(vars, cert).await?;
-        let rand_id = rand::thread_rng().gen::<u128>();
-        let file_name = field
-            .content_disposition()
-            .get_filename()
-            .ok_or(UploadError::BadFileName)?;
+        // Waits for the partial file to be deleted when the upload failed.
+        let stored = stored.await;
 
-        conn.put_file(format!("{rand_id}-{file_name}"), &mut &bytes_vec[..])
-            .await?;
+        streamed?;
 
-        Ok(())
+        Ok(stored?)
     }
 
-    let (var, cert) = verify_var_cert!(req);
+    let (var, roots) = verify_var_roots!(req);
 
-    match ftp_upload(var, cert, multi_part).await {
+    match ftp_upload(var, roots, multi_part).await {
         Ok(()) => HttpResponse::Ok().finish(),
         Err(UpFtpError(FtpError::UnexpectedResponse(Response {
             status: Status::BadFilename,
             ..
-        }))) => HttpResponse::BadRequest().json(ErrorResponse {
+        })))
+        | Err(BadFileName) => HttpResponse::BadRequest().json(ErrorResponse {
             error: "Bad file name".to_string(),
         }),
-        Err(UpMultipartError(_) | NoData | BadFileName) => {
-            HttpResponse::BadRequest().json(ErrorResponse {
-                error: "Malformed multipart file".to_string(),
-            })
-        }
+        Err(UpMultipartError(_) | NoData) => HttpResponse::BadRequest().json(ErrorResponse {
+            error: "Malformed multipart file".to_string(),
+        }),
+        Err(TooLarge(max)) => HttpResponse::PayloadTooLarge().json(ErrorResponse {
+            error: format!("File is over the {max} byte limit"),
+        }),
         Err(UpFtpError(err)) => {
             error!("Encountered FTP error while uploading file: {err}");
 
@@ -207,25 +360,124 @@ async fn upload_file(req: HttpRequest, multi_part: Multipart) -> impl Responder
     }
 }
 
+/// Forwards ``len`` bytes of the FTP data stream to ``chunks``, the receiver of which is the response body. The
+/// transfer is finalized on the control connection if the body ends at the end of the file. Otherwise, or if the client
+/// hangs up, the connection is dropped, since stopping a transfer early aborts it.
+fn send_download(
+    mut conn: FtpStream,
+    mut data: impl Read,
+    len: u64,
+    to_eof: bool,
+    mut chunks: Sender<io::Result<Bytes>>,
+) {
+    let mut remaining = len;
+
+    while remaining > 0 {
+        let chunk_size = usize::try_from(remaining).map_or(DOWNLOAD_CHUNK_SIZE, |remaining| {
+            remaining.min(DOWNLOAD_CHUNK_SIZE)
+        });
+        let mut buf = vec![0; chunk_size];
+        let chunk = match data.read(&mut buf) {
+            Ok(0) => {
+                error!("FTP server ended the file {remaining} bytes early");
+
+                Err(io::ErrorKind::UnexpectedEof.into())
+            }
+            Ok(read) => {
+                buf.truncate(read);
+                remaining -= read as u64;
+
+                Ok(Bytes::from(buf))
+            }
+            Err(err) => {
+                error!("Encountered IO error downloading file: {err}");
+
+                Err(err)
+            }
+        };
+        let failed = chunk.is_err();
+
+        if executor::block_on(chunks.send(chunk)).is_err() || failed {
+            return;
+        }
+    }
+
+    if to_eof {
+        if let Err(err) = conn.finalize_retr_stream(data) {
+            error!("We couldn't finalize the FTP stream: {err}");
+
+            let _ = executor::block_on(chunks.send(Err(io::Error::other(err))));
+        }
+    }
+}
+
+/// The part of a file a download asks for.
+#[derive(Debug, PartialEq, Eq)]
+enum RequestedRange {
+    Full,
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Picks the bytes to send from the ``Range`` and ``If-Range`` headers. Multiple ranges, and ranges of a file that
+/// changed since the ``If-Range`` validator, are answered with the whole file. Since the ETag is weak, an ``If-Range``
+/// ETag never matches.
+fn requested_range(req: &HttpRequest, file: &File) -> RequestedRange {
+    let specs = match Range::parse(req) {
+        Ok(Range::Bytes(specs)) if specs.len() == 1 => specs,
+        _ => return RequestedRange::Full,
+    };
+    let unchanged = match IfRange::parse(req) {
+        Ok(IfRange::EntityTag(tag)) => tag.strong_eq(&file.etag()),
+        Ok(IfRange::Date(date)) => date == HttpDate::from(file.modified),
+        Err(_) => !req.headers().contains_key(header::IF_RANGE),
+    };
+
+    if !unchanged {
+        return RequestedRange::Full;
+    }
+
+    match specs[0].to_satisfiable_range(file.size) {
+        Some((start, end)) => RequestedRange::Partial(start, end),
+        None => RequestedRange::Unsatisfiable,
+    }
+}
+
 #[get("/{file_id}")]
-async fn get_file_by_id(req: HttpRequest, path: Path<u128>) -> impl Responder {
-    async fn download_file(
+async fn get_file_by_id(req: HttpRequest, path: Path<String>) -> impl Responder {
+    /// Starts downloading the file from ``offset``, which is sent to the FTP server with ``REST``.
+    fn download_file(
         vars: &BackendVars,
-        tls_cert: &Certificate,
-        name: String,
-    ) -> FtpResult<(impl AsyncRead, FtpStream)> {
-        let mut conn = secure_ftp_login(vars, tls_cert).await?;
+        roots: &TrustedRoots,
+        file_name: &str,
+        offset: u64,
+    ) -> FtpResult<(FtpStream, impl Read + Send + 'static)> {
+        let mut conn = secure_ftp_login(vars, roots)?;
+
+        if offset > 0 {
+            let offset = usize::try_from(offset).map_err(|_| FtpError::BadResponse)?;
+
+            conn.resume_transfer(offset)?;
+        }
 
-        Ok((conn.retr_as_stream(name).await?, conn))
+        let data = conn.retr_as_stream(file_name)?;
+
+        Ok((conn, data))
     }
 
-    let (var, cert) = verify_var_cert!(req);
+    let (var, roots) = verify_var_roots!(req);
+    let (var, roots) = (var.clone(), roots.clone());
+
+    verify_permission!(req, Permission::DownloadFiles);
 
-    verify_admin_token!(req, var);
+    let file_id = path.into_inner();
 
-    let file_id = path.to_string();
+    let listed = {
+        let (var, roots) = (var.clone(), roots.clone());
 
-    let files = match list_files(var, cert).await {
+        run_blocking(move || list_files(&var, &roots))
+    };
+    let files = match listed.await {
         Ok(files) => files,
         Err(err) => {
             error!("Encountered internal error while listing files from FTP server: {err}");
@@ -242,29 +494,53 @@ async fn get_file_by_id(req: HttpRequest, path: Path<u128>) -> impl Responder {
         }
     };
 
-    match download_file(var, cert, format!("{}-{}", found_file.id, found_file.name)).await {
-        Ok((mut data, mut stream)) => {
-            let mut data_vec = Vec::new();
-            if let Err(err) = data.read_to_end(&mut data_vec).await {
-                error!("Encountered IO error downloading file: {err}");
+    let (mut res, offset, len) = match requested_range(&req, &found_file) {
+        RequestedRange::Full => (HttpResponse::Ok(), 0, found_file.size),
+        RequestedRange::Partial(start, end) => {
+            let mut res = HttpResponse::PartialContent();
 
-                return internal_server_error();
-            }
+            res.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                range: Some((start, end)),
+                instance_length: Some(found_file.size),
+            }));
+
+            (res, start, end - start + 1)
+        }
+        RequestedRange::Unsatisfiable => {
+            return HttpResponse::RangeNotSatisfiable()
+                .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(found_file.size),
+                }))
+                .finish()
+        }
+    };
 
-            if let Err(err) = stream.finalize_retr_stream(data).await {
-                error!("We couldn't finalize the FTP stream: {err}");
+    let file_name = format!("{}-{}", found_file.id, found_file.name);
+    let to_eof = offset + len == found_file.size;
 
-                internal_server_error()
-            } else {
-                HttpResponse::Ok().body(data_vec)
-            }
+    match run_blocking(move || download_file(&var, &roots, &file_name, offset)).await {
+        Ok((conn, data)) => {
+            let (chunks, body) = mpsc::channel(0);
+
+            task::spawn_blocking(move || send_download(conn, data, len, to_eof, chunks));
+
+            res.content_type(mime::APPLICATION_OCTET_STREAM)
+                .insert_header((header::ACCEPT_RANGES, "bytes"))
+                .insert_header(ETag(found_file.etag()))
+                .insert_header(LastModified(HttpDate::from(found_file.modified)))
+                .insert_header(ContentDisposition {
+                    disposition: DispositionType::Attachment,
+                    parameters: vec![DispositionParam::Filename(found_file.name)],
+                })
+                .body(SizedStream::new(len, body))
         }
         Err(err) => match err {
             FtpError::UnexpectedResponse(Response {
                 status: Status::BadFilename,
                 ..
             }) => HttpResponse::BadRequest().json(ErrorResponse {
-                error: format!("File with requested ID doesn't exist."),
+                error: "File with requested ID doesn't exist.".to_string(),
             }),
             err => {
                 error!("We had an internal error while trying to get our FTP file: {err}");
@@ -275,8 +551,75 @@ async fn get_file_by_id(req: HttpRequest, path: Path<u128>) -> impl Responder {
     }
 }
 
-pub(crate) fn file_endpoint_config(cfg: &mut ServiceConfig) {
-    cfg.service(get_files)
-        .service(upload_file)
-        .service(upload_file);
+/// Which of the file endpoints are served, set by ``FILE_ENDPOINTS``.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FileEndpoints {
+    list: bool,
+    upload: bool,
+    download: bool,
 }
+
+impl FileEndpoints {
+    /// Parses the comma separated ``FILE_ENDPOINTS`` of ``list``, ``upload`` and ``download``. All of them are served if
+    /// it's unset, and none if it's empty.
+    pub fn from_vars(vars: &BackendVars) -> io::Result<Self> {
+        let endpoints = match vars.file_endpoints.as_deref() {
+            Some(endpoints) => endpoints,
+            None => {
+                return Ok(Self {
+                    list: true,
+                    upload: true,
+                    download: true,
+                })
+            }
+        };
+        let mut enabled = Self {
+            list: false,
+            upload: false,
+            download: false,
+        };
+
+        for endpoint in endpoints
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+        {
+            match endpoint {
+                "list" => enabled.list = true,
+                "upload" => enabled.upload = true,
+                "download" => enabled.download = true,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Bad FILE_ENDPOINTS endpoint {endpoint:?}. Expected list, upload or download"
+                        ),
+                    ))
+                }
+            }
+        }
+
+        Ok(enabled)
+    }
+
+    pub fn any(&self) -> bool {
+        self.list || self.upload || self.download
+    }
+}
+
+pub(crate) fn file_endpoint_config(cfg: &mut ServiceConfig, endpoints: FileEndpoints) {
+    if endpoints.list {
+        cfg.service(get_files);
+    }
+
+    if endpoints.upload {
+        cfg.service(upload_file);
+    }
+
+    if endpoints.download {
+        cfg.service(get_file_by_id);
+    }
+}
+
+#[cfg(test)]
+mod tests;
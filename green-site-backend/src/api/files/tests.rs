@@ -0,0 +1,630 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::Ipv4Addr,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    http::{header, StatusCode},
+    rt::{
+        self,
+        net::{TcpListener, TcpStream},
+    },
+    test::{call_and_read_body_json, call_service, init_service, read_body, TestRequest},
+    web, App, HttpServer,
+};
+use rcgen::{BasicConstraints, CertificateParams, DnType, IsCa};
+use rustls::{Certificate, PrivateKey, ServerConfig};
+use serde_json::Value;
+use sqlx::SqlitePool;
+use tempfile::TempDir;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio_rustls::TlsAcceptor;
+
+use super::*;
+use crate::{db, role::Role, session, tls_policy::TlsPolicy, token::TokenMode, users};
+
+const BOUNDARY: &str = "green-site-test-boundary";
+const CONTENTS: &[u8] = b"Hello from the FTPS stub!";
+
+/// The files stored by an ``FtpsStub``, by name.
+type StubFiles = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+/// The commands an ``FtpsStub`` received, in order.
+type StubCommands = Arc<Mutex<Vec<String>>>;
+
+/// An in-process FTPS server with explicit TLS on the control and data connections. It knows just enough commands
+/// for the file endpoints and keeps the files in memory.
+struct FtpsStub {
+    port: u16,
+    files: StubFiles,
+    commands: StubCommands,
+}
+
+impl FtpsStub {
+    async fn start(cert: Certificate, key: PrivateKey) -> Self {
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert], key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let files = StubFiles::default();
+        let commands = StubCommands::default();
+        let (stub_files, stub_commands) = (files.clone(), commands.clone());
+
+        rt::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                rt::spawn(serve_control(
+                    stream,
+                    acceptor.clone(),
+                    stub_files.clone(),
+                    stub_commands.clone(),
+                ));
+            }
+        });
+
+        Self {
+            port,
+            files,
+            commands,
+        }
+    }
+
+    fn files(&self) -> HashMap<String, Vec<u8>> {
+        self.files.lock().unwrap().clone()
+    }
+
+    fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+fn list_line(name: &str, contents: &[u8]) -> String {
+    format!(
+        "-rw-r--r-- 1 ftp ftp {} Nov 05 2021 {name}\r\n",
+        contents.len()
+    )
+}
+
+async fn serve_control(
+    mut stream: TcpStream,
+    acceptor: TlsAcceptor,
+    files: StubFiles,
+    commands: StubCommands,
+) -> io::Result<()> {
+    stream.write_all(b"220 Ready\r\n").await?;
+
+    let mut plain = BufReader::new(stream);
+    let mut line = String::new();
+
+    plain.read_line(&mut line).await?;
+
+    if line.trim_end() != "AUTH TLS" {
+        return plain.get_mut().write_all(b"530 TLS required\r\n").await;
+    }
+
+    plain.get_mut().write_all(b"234 AUTH TLS OK\r\n").await?;
+
+    let mut control = BufReader::new(acceptor.accept(plain.into_inner()).await?);
+    let mut passive: Option<TcpListener> = None;
+    let mut offset = 0;
+
+    'commands: loop {
+        line.clear();
+
+        if control.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+
+        let command = line.trim_end();
+
+        commands.lock().unwrap().push(command.to_string());
+
+        let (verb, arg) = command.split_once(' ').unwrap_or((command, ""));
+        let reply = match verb {
+            "USER" => "331 Password required".to_string(),
+            "PASS" => "230 Logged in".to_string(),
+            "PBSZ" | "PROT" | "TYPE" => "200 OK".to_string(),
+            "PASV" => {
+                let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+                let port = listener.local_addr()?.port();
+
+                passive = Some(listener);
+
+                format!(
+                    "227 Entering Passive Mode (127,0,0,1,{},{})",
+                    port >> 8,
+                    port & 0xff
+                )
+            }
+            "REST" => {
+                offset = arg.parse().unwrap_or(0);
+
+                format!("350 Restarting at {offset}")
+            }
+            "DELE" => match files.lock().unwrap().remove(arg) {
+                Some(_) => "250 Deleted".to_string(),
+                None => "550 No such file".to_string(),
+            },
+            "LIST" | "RETR" | "STOR" => {
+                let listener = match passive.take() {
+                    Some(listener) => listener,
+                    None => {
+                        control.write_all(b"425 Use PASV first\r\n").await?;
+                        continue;
+                    }
+                };
+
+                control
+                    .write_all(b"150 Opening data connection\r\n")
+                    .await?;
+
+                let (data, _) = listener.accept().await?;
+                // The handshake only happens once the client reads or writes, so an upload aborted before any data
+                // is written closes the connection without one.
+                let mut data = match acceptor.accept(data).await {
+                    Ok(data) => data,
+                    Err(_) => {
+                        control
+                            .write_all(b"425 Can't open data connection\r\n")
+                            .await?;
+                        continue;
+                    }
+                };
+
+                match verb {
+                    "LIST" => {
+                        let listing: String = files
+                            .lock()
+                            .unwrap()
+                            .iter()
+                            .map(|(name, contents)| list_line(name, contents))
+                            .collect();
+
+                        data.write_all(listing.as_bytes()).await?;
+                        data.shutdown().await?;
+                    }
+                    "RETR" => {
+                        let contents = files.lock().unwrap().get(arg).cloned();
+                        let contents = contents.unwrap_or_default();
+                        let start = offset.min(contents.len());
+
+                        // Ranged downloads hang up early, so write errors are expected.
+                        let _ = data.write_all(&contents[start..]).await;
+                        let _ = data.shutdown().await;
+                    }
+                    _ => {
+                        let mut buf = [0; 1024];
+
+                        files.lock().unwrap().insert(arg.to_string(), Vec::new());
+
+                        // Like a real server, the file is written as the data arrives. The client closes the data
+                        // connection without a TLS close_notify.
+                        loop {
+                            let read = match data.read(&mut buf).await {
+                                Ok(0) => break,
+                                Ok(read) => read,
+                                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+                                // A client hanging up mid upload resets the connection.
+                                Err(_) => {
+                                    control
+                                        .write_all(b"426 Connection closed; transfer aborted\r\n")
+                                        .await?;
+
+                                    continue 'commands;
+                                }
+                            };
+
+                            files
+                                .lock()
+                                .unwrap()
+                                .entry(arg.to_string())
+                                .or_default()
+                                .extend_from_slice(&buf[..read]);
+                        }
+                    }
+                }
+
+                offset = 0;
+
+                "226 Transfer complete".to_string()
+            }
+            "QUIT" => {
+                control.write_all(b"221 Bye\r\n").await?;
+
+                return Ok(());
+            }
+            _ => "502 Not implemented".to_string(),
+        };
+
+        control.write_all(format!("{reply}\r\n").as_bytes()).await?;
+    }
+}
+
+/// A CA, and a certificate for localhost signed by it. Returns the CA as PEM with the certificate and its key.
+fn certificates() -> (String, Certificate, PrivateKey) {
+    let mut ca_params = CertificateParams::new(Vec::new());
+
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    ca_params
+        .distinguished_name
+        .push(DnType::CommonName, "Green Site Test CA");
+
+    let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+    let params = CertificateParams::new(vec!["localhost".to_string()]);
+    let cert = rcgen::Certificate::from_params(params).unwrap();
+
+    (
+        ca.serialize_pem().unwrap(),
+        Certificate(cert.serialize_der_with_signer(&ca).unwrap()),
+        PrivateKey(cert.serialize_private_key_der()),
+    )
+}
+
+/// Settings for the FTPS stub, with the stub's CA written to ``dir``.
+fn test_vars(dir: &TempDir, ftps_port: u16, ca_pem: &str) -> BackendVars {
+    let ca_path = dir.path().join("ca.pem");
+
+    fs::write(&ca_path, ca_pem).unwrap();
+
+    BackendVars {
+        ftps_server_port: ftps_port,
+        // rustls can only verify certificates for DNS names, not IP addresses.
+        ftps_tls_name: Some("localhost".to_string()),
+        root_certificate_path: ca_path.to_str().unwrap().to_string(),
+        ..BackendVars::for_tests()
+    }
+}
+
+/// An admin's session token in a freshly migrated DB.
+async fn admin_token() -> (SqlitePool, String) {
+    let pool = db::memory_pool().await;
+
+    users::create_user(&pool, "admin", "unused", Role::Admin)
+        .await
+        .unwrap();
+
+    let token = session::create_session(&pool, "admin").await.unwrap();
+
+    (pool, token.as_str().to_string())
+}
+
+/// A multipart body of one file field, without the closing boundary so it can be cut short.
+fn upload_body(file_name: &str, contents: &[u8]) -> Vec<u8> {
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\n\
+         Content-Type: text/plain\r\n\r\n"
+    )
+    .into_bytes();
+
+    body.extend_from_slice(contents);
+
+    body
+}
+
+fn upload_request(file_name: &str) -> TestRequest {
+    let mut body = upload_body(file_name, CONTENTS);
+
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+
+    TestRequest::post()
+        .uri("/files")
+        .insert_header((
+            header::CONTENT_TYPE,
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        ))
+        .set_payload(body)
+}
+
+/// Polls the stub until ``done`` holds, for up to 5 seconds.
+async fn wait_for(stub: &FtpsStub, done: impl Fn(&FtpsStub) -> bool) -> bool {
+    for _ in 0..100 {
+        if done(stub) {
+            return true;
+        }
+
+        rt::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    false
+}
+
+/// Whether the stub deleted the file of its first ``STOR``, after that ``STOR`` ended.
+fn deleted_stored_file(stub: &FtpsStub) -> bool {
+    let commands = stub.commands();
+    let stored = match commands.iter().position(|c| c.starts_with("STOR ")) {
+        Some(stored) => stored,
+        None => return false,
+    };
+    let name = &commands[stored]["STOR ".len()..];
+
+    commands[stored..].contains(&format!("DELE {name}")) && stub.files().is_empty()
+}
+
+#[actix_web::test]
+async fn uploads_lists_and_downloads_over_ftps() {
+    let dir = TempDir::new().unwrap();
+    let (ca_pem, cert, key) = certificates();
+    let stub = FtpsStub::start(cert, key).await;
+    let vars = test_vars(&dir, stub.port, &ca_pem);
+    let roots = TrustedRoots::load(&vars, TlsPolicy::from_vars(&vars).unwrap()).unwrap();
+    let endpoints = FileEndpoints::from_vars(&vars).unwrap();
+    let (pool, token) = admin_token().await;
+    let bearer = (header::AUTHORIZATION, format!("Bearer {token}"));
+    let app = init_service(
+        App::new()
+            .app_data(vars)
+            .app_data(pool)
+            .app_data(TokenMode::Session)
+            .app_data(roots)
+            .service(web::scope("/files").configure(|cfg| file_endpoint_config(cfg, endpoints))),
+    )
+    .await;
+
+    let res = call_service(&app, upload_request("../escape.txt").to_request()).await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert!(stub.files().is_empty());
+
+    let res = call_service(&app, upload_request("hello.txt").to_request()).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let stored = stub.files();
+    let (stored_name, stored_contents) = stored.iter().next().unwrap();
+
+    assert_eq!(stored.len(), 1);
+    assert!(stored_name.ends_with("-hello.txt"));
+    assert_eq!(stored_contents, CONTENTS);
+
+    let req = TestRequest::get()
+        .uri("/files")
+        .insert_header(bearer.clone())
+        .to_request();
+    let listed: Vec<Value> = call_and_read_body_json(&app, req).await;
+
+    assert_eq!(listed.len(), 1);
+    assert_eq!(listed[0]["name"], "hello.txt");
+    assert_eq!(listed[0]["size"], CONTENTS.len());
+
+    let id = listed[0]["id"].as_str().unwrap();
+    let req = TestRequest::get()
+        .uri(&format!("/files/{id}"))
+        .insert_header(bearer.clone())
+        .to_request();
+    let res = call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers().contains_key(header::ETAG));
+    assert_eq!(
+        res.headers().get(header::CONTENT_TYPE).unwrap(),
+        "application/octet-stream"
+    );
+    assert_eq!(
+        res.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+        "attachment; filename=\"hello.txt\""
+    );
+    // Content-Length is written from the body's size, which comes from the listing.
+    assert_eq!(
+        res.response().body().size(),
+        BodySize::Sized(CONTENTS.len() as u64)
+    );
+    assert_eq!(read_body(res).await, CONTENTS);
+
+    let req = TestRequest::get()
+        .uri(&format!("/files/{id}"))
+        .insert_header(bearer)
+        .insert_header((header::RANGE, "bytes=6-9"))
+        .to_request();
+    let res = call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(res.response().body().size(), BodySize::Sized(4));
+    assert_eq!(read_body(res).await, &CONTENTS[6..=9]);
+}
+
+#[actix_web::test]
+async fn disabled_endpoints_arent_routed() {
+    let vars = BackendVars {
+        file_endpoints: Some("list".to_string()),
+        ..BackendVars::for_tests()
+    };
+    let endpoints = FileEndpoints::from_vars(&vars).unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(vars)
+            .service(web::scope("/files").configure(|cfg| file_endpoint_config(cfg, endpoints))),
+    )
+    .await;
+
+    let res = call_service(&app, upload_request("hello.txt").to_request()).await;
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let req = TestRequest::get().uri("/files/1").to_request();
+    let res = call_service(&app, req).await;
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    // The list endpoint is still routed, and refuses requests without a session.
+    let req = TestRequest::get().uri("/files").to_request();
+    let res = call_service(&app, req).await;
+
+    assert_ne!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn file_endpoints_parse_the_toggles() {
+    let endpoints = |file_endpoints: Option<&str>| {
+        FileEndpoints::from_vars(&BackendVars {
+            file_endpoints: file_endpoints.map(str::to_string),
+            ..BackendVars::for_tests()
+        })
+    };
+
+    assert!(endpoints(None).unwrap().any());
+    assert!(!endpoints(Some("")).unwrap().any());
+
+    let enabled = endpoints(Some(" upload,download ")).unwrap();
+
+    assert!(!enabled.list && enabled.upload && enabled.download);
+    assert!(endpoints(Some("delete")).is_err());
+}
+
+#[actix_web::test]
+async fn over_limit_uploads_are_deleted() {
+    let dir = TempDir::new().unwrap();
+    let (ca_pem, cert, key) = certificates();
+    let stub = FtpsStub::start(cert, key).await;
+    let vars = BackendVars {
+        max_upload_bytes: Some(10),
+        ..test_vars(&dir, stub.port, &ca_pem)
+    };
+    let roots = TrustedRoots::load(&vars, TlsPolicy::from_vars(&vars).unwrap()).unwrap();
+    let endpoints = FileEndpoints::from_vars(&vars).unwrap();
+    let app = init_service(
+        App::new()
+            .app_data(vars)
+            .app_data(roots)
+            .service(web::scope("/files").configure(|cfg| file_endpoint_config(cfg, endpoints))),
+    )
+    .await;
+
+    let res = call_service(&app, upload_request("hello.txt").to_request()).await;
+
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    // The partial file is deleted before responding.
+    assert!(deleted_stored_file(&stub));
+}
+
+#[actix_web::test]
+async fn disconnected_uploads_are_deleted() {
+    let dir = TempDir::new().unwrap();
+    let (ca_pem, cert, key) = certificates();
+    let stub = FtpsStub::start(cert, key).await;
+    let vars = test_vars(&dir, stub.port, &ca_pem);
+    let roots = TrustedRoots::load(&vars, TlsPolicy::from_vars(&vars).unwrap()).unwrap();
+    let endpoints = FileEndpoints::from_vars(&vars).unwrap();
+    let listener = std::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = HttpServer::new(move || {
+        App::new()
+            .app_data(vars.clone())
+            .app_data(roots.clone())
+            .service(web::scope("/files").configure(|cfg| file_endpoint_config(cfg, endpoints)))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let server_handle = server.handle();
+
+    rt::spawn(server);
+
+    // Enough data for the multipart parser to pass some on before it sees a boundary.
+    let body = upload_body("hello.txt", &[b'x'; 16 * 1024]);
+    let head = format!(
+        "POST /files HTTP/1.1\r\nHost: localhost\r\n\
+         Content-Type: multipart/form-data; boundary={BOUNDARY}\r\nContent-Length: {}\r\n\r\n",
+        body.len() + 1024
+    );
+    let mut client = TcpStream::connect(addr).await.unwrap();
+
+    client.write_all(head.as_bytes()).await.unwrap();
+    client.write_all(&body).await.unwrap();
+
+    assert!(
+        wait_for(&stub, |stub| stub
+            .files()
+            .values()
+            .any(|contents| !contents.is_empty()))
+        .await
+    );
+
+    drop(client);
+
+    assert!(wait_for(&stub, deleted_stored_file).await);
+
+    server_handle.stop(false).await;
+}
+
+fn listed_file() -> File {
+    File {
+        name: "report.csv".to_string(),
+        id: "1".to_string(),
+        size: 100,
+        modified: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+    }
+}
+
+fn range_of(headers: &[(header::HeaderName, String)]) -> RequestedRange {
+    let req = headers
+        .iter()
+        .fold(TestRequest::default(), |req, header| {
+            req.insert_header(header.clone())
+        })
+        .to_http_request();
+
+    requested_range(&req, &listed_file())
+}
+
+#[test]
+fn requested_range_without_header_is_full() {
+    assert_eq!(range_of(&[]), RequestedRange::Full);
+}
+
+#[test]
+fn requested_range_single_range_is_partial() {
+    let range = (header::RANGE, "bytes=10-19".to_string());
+
+    assert_eq!(range_of(&[range]), RequestedRange::Partial(10, 19));
+
+    let suffix = (header::RANGE, "bytes=-10".to_string());
+
+    assert_eq!(range_of(&[suffix]), RequestedRange::Partial(90, 99));
+}
+
+#[test]
+fn requested_range_multiple_ranges_are_full() {
+    let range = (header::RANGE, "bytes=0-4,10-14".to_string());
+
+    assert_eq!(range_of(&[range]), RequestedRange::Full);
+}
+
+#[test]
+fn requested_range_checks_if_range() {
+    let range = (header::RANGE, "bytes=10-19".to_string());
+    let modified = HttpDate::from(listed_file().modified).to_string();
+    let stale = HttpDate::from(listed_file().modified - Duration::from_secs(60)).to_string();
+    let etag = listed_file().etag().to_string();
+
+    assert_eq!(
+        range_of(&[range.clone(), (header::IF_RANGE, modified)]),
+        RequestedRange::Partial(10, 19)
+    );
+    assert_eq!(
+        range_of(&[range.clone(), (header::IF_RANGE, stale)]),
+        RequestedRange::Full
+    );
+    // The ETag is weak, so it can't be used to resume.
+    assert_eq!(
+        range_of(&[range.clone(), (header::IF_RANGE, etag)]),
+        RequestedRange::Full
+    );
+    assert_eq!(
+        range_of(&[range, (header::IF_RANGE, "garbage".to_string())]),
+        RequestedRange::Full
+    );
+}
+
+#[test]
+fn requested_range_past_the_end_is_unsatisfiable() {
+    let range = (header::RANGE, "bytes=100-199".to_string());
+
+    assert_eq!(range_of(&[range]), RequestedRange::Unsatisfiable);
+}
@@ -1,24 +1,34 @@
+use std::{error::Error, time::Duration};
+
 use actix_web::{
+    http::header,
     post,
     web::{Json, JsonConfig, ServiceConfig},
     HttpRequest, HttpResponse,
 };
-use log::{error, warn};
+use log::error;
 
 use serde::{Deserialize, Serialize};
-use sqlx::{Connection, Row, SqliteConnection};
+use sqlx::SqlitePool;
 
 use crate::{
-    env_vars::BackendVars,
+    credentials::{CredentialBackend, SharedCredentialBackend},
+    csrf,
     error::{self, ErrorResponse, MISSING_APP_DATA},
-    token::AdminToken,
+    listener,
+    login_limit::LoginLimiter,
+    role::Role,
+    security_log::{self, AuthFailureReason},
+    session,
+    token::{auth_cookies_enabled, session_cookie, SessionToken, TokenMode},
+    users,
 };
 
 const MIN_USERNAME_LEN: usize = 1;
 const MIN_PASSWORD_LEN: usize = 1;
-const MAX_USERNAME_LEN: usize = 72;
-const MAX_PASSWORD_LEN: usize = 72;
-const BUFFER_SPACE: usize = 50;
+pub(super) const MAX_USERNAME_LEN: usize = 72;
+pub(super) const MAX_PASSWORD_LEN: usize = 72;
+pub(super) const BUFFER_SPACE: usize = 50;
 
 #[derive(Deserialize)]
 struct UserLogin {
@@ -26,96 +36,381 @@ struct UserLogin {
     password: String,
 }
 
+#[derive(Deserialize)]
+struct MfaLogin {
+    challenge: String,
+    code: String,
+}
+
+/// When the user has TOTP enabled, ``token`` and ``role`` are only sent after the ``mfa_challenge`` is answered.
 #[derive(Serialize)]
 struct Authentication {
     #[serde(skip)]
     is_valid: bool,
-    token: Option<AdminToken>,
+    token: Option<SessionToken>,
+    role: Option<Role>,
+    mfa_required: bool,
+    mfa_challenge: Option<String>,
 }
 
-/// Checks that the user attempting to login has the correct credentials.
-/// Returns whether the user is an admin or not if credentials could be validated.
+/// Checks that the user attempting to login has the correct credentials with the configured credential backend.
+/// Starts a new session for the user if credentials could be validated, or issues a TOTP challenge if they have TOTP enabled.
 async fn check_credentials(
     user_login: &UserLogin,
-    vars: &BackendVars,
-) -> sqlx::Result<Authentication> {
+    pool: &SqlitePool,
+    backend: &dyn CredentialBackend,
+    token_mode: &TokenMode,
+) -> Result<Authentication, Box<dyn Error>> {
     // CRITICAL CODE: ENSURE AN OK RESULT CAN NEVER BE RETURNED IF THE CREDENTIALS ARE INVALID.
 
-    let mut conn = SqliteConnection::connect(&vars.sqlite_file_name).await?;
-    let pwd: Option<String> = sqlx::query("SELECT password FROM users WHERE username=?;")
-        .bind(user_login.username.as_str())
-        .fetch_optional(&mut conn)
-        .await?
-        .and_then(|pwd| pwd.try_get(0).ok())
-        .filter(|pwd| pwd == &user_login.password);
-    let (is_valid, used_admin_username) = (
-        pwd.is_some(),
-        user_login.username == vars.admin_account_username,
-    );
+    let is_valid = backend
+        .verify(pool, &user_login.username, &user_login.password)
+        .await?;
+    // Disabled users are treated the same as bad credentials.
+    let account = match is_valid {
+        true => users::login_account(pool, &user_login.username).await?,
+        false => None,
+    };
+    let (token, role, mfa_challenge) = match account {
+        Some((_, true)) => (
+            None,
+            None,
+            Some(session::create_mfa_challenge(pool, &user_login.username).await?),
+        ),
+        Some((role, false)) => (
+            Some(token_mode.issue(pool, &user_login.username, role).await?),
+            Some(role),
+            None,
+        ),
+        None => (None, None, None),
+    };
 
     Ok(Authentication {
-        is_valid,
-        token: (is_valid && used_admin_username).then(|| AdminToken::new(vars.admin_token.clone())),
+        is_valid: account.is_some(),
+        token,
+        role,
+        mfa_required: mfa_challenge.is_some(),
+        mfa_challenge,
     })
 }
 
-#[post("")]
-async fn login(req: HttpRequest, user_login: Json<UserLogin>) -> HttpResponse {
-    if !(MIN_USERNAME_LEN..MAX_USERNAME_LEN).contains(&user_login.username.len()) {
-        return HttpResponse::BadRequest().json(ErrorResponse {
+/// Checks the username is the right length and all lowercase ASCII characters.
+pub(super) fn username_error(username: &str) -> Option<ErrorResponse> {
+    if !(MIN_USERNAME_LEN..MAX_USERNAME_LEN).contains(&username.len()) {
+        Some(ErrorResponse {
             error: format!(
                 "Username must be between {MIN_USERNAME_LEN} and {MAX_USERNAME_LEN} characters."
             ),
-        });
-    } else if !(MIN_PASSWORD_LEN..MAX_PASSWORD_LEN).contains(&user_login.password.len()) {
-        return HttpResponse::BadRequest().json(ErrorResponse {
+        })
+    } else if !username.bytes().all(|b| b.is_ascii_lowercase()) {
+        Some(ErrorResponse {
+            error: "Username must be all lowercase ASCII charcters.".to_string(),
+        })
+    } else {
+        None
+    }
+}
+
+/// Checks the password is the right length.
+pub(super) fn password_error(password: &str) -> Option<ErrorResponse> {
+    if !(MIN_PASSWORD_LEN..MAX_PASSWORD_LEN).contains(&password.len()) {
+        Some(ErrorResponse {
             error: format!(
                 "Password must be between {MIN_PASSWORD_LEN} and {MAX_PASSWORD_LEN} characters."
             ),
-        });
-    } else if !user_login.username.bytes().all(|b| b.is_ascii_lowercase()) {
-        return HttpResponse::BadRequest().json(ErrorResponse {
-            error: format!("Username must be all lowercase ASCII charcters."),
-        });
+        })
+    } else {
+        None
+    }
+}
+
+pub(super) fn too_many_attempts(retry_after: Duration) -> HttpResponse {
+    // Rounded up, so a client waiting exactly that long isn't turned away again.
+    let retry_secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+
+    HttpResponse::TooManyRequests()
+        .insert_header((header::RETRY_AFTER, retry_secs.max(1).to_string()))
+        .json(ErrorResponse {
+            error: "Too many login attempts. Please try again later.".to_string(),
+        })
+}
+
+/// Responds with the authentication. If ``AUTH_COOKIES`` is enabled, the token is only set as a cookie along with a
+/// new CSRF token, so it's never readable by JS.
+fn authenticated_response(req: &HttpRequest, mut authed: Authentication) -> HttpResponse {
+    if !auth_cookies_enabled(req) {
+        return HttpResponse::Ok().json(authed);
+    }
+
+    let mut res = HttpResponse::Ok();
+
+    if let Some(token) = authed.token.take() {
+        res.cookie(session_cookie(&token))
+            .cookie(csrf::csrf_cookie());
+    }
+
+    res.json(authed)
+}
+
+#[post("")]
+async fn login(req: HttpRequest, user_login: Json<UserLogin>) -> HttpResponse {
+    if let Some(err) =
+        username_error(&user_login.username).or_else(|| password_error(&user_login.password))
+    {
+        return HttpResponse::BadRequest().json(err);
     }
 
-    if let Some(vars) = req.app_data::<BackendVars>() {
-        match check_credentials(&user_login.0, &vars).await {
+    if let (Some(pool), Some(limiter), Some(backend), Some(token_mode)) = (
+        req.app_data::<SqlitePool>(),
+        req.app_data::<LoginLimiter>(),
+        req.app_data::<SharedCredentialBackend>(),
+        req.app_data::<TokenMode>(),
+    ) {
+        let peer_addr = match listener::client_addr(&req) {
+            Some(addr) => addr,
+            None => return listener::unknown_client(),
+        };
+
+        if let Err(retry_after) = limiter.check(&peer_addr, &user_login.username) {
+            security_log::auth_failure(
+                Some(&peer_addr),
+                Some(&user_login.username),
+                AuthFailureReason::RateLimited,
+            );
+
+            return too_many_attempts(retry_after);
+        }
+
+        match check_credentials(&user_login.0, pool, backend.as_ref(), token_mode).await {
             Ok(Authentication {
                 is_valid: false, ..
             }) => {
-                warn!(
-                    "{:?} tried logging in with bad credentials. Username: {}. Password: {}",
-                    req.connection_info().peer_addr(),
-                    user_login.0.username,
-                    user_login.0.password,
+                security_log::auth_failure(
+                    Some(&peer_addr),
+                    Some(&user_login.username),
+                    AuthFailureReason::BadCredentials,
                 );
+                limiter.record_failure(&user_login.username).await;
 
                 HttpResponse::BadRequest().json(ErrorResponse {
                     error: "Bad credentials.".to_string(),
                 })
             }
-            Ok(authed) => HttpResponse::Ok().json(authed),
+            Ok(authed) => {
+                // With TOTP enabled, failures are only forgiven once ``login_mfa`` checks the second factor.
+                if !authed.mfa_required {
+                    limiter.record_success(&user_login.username).await;
+                }
+
+                authenticated_response(&req, authed)
+            }
             Err(err) => {
-                error!("Encountered sqlx error: {err}. Sending 500 response code...");
+                error!("Encountered error while checking credentials: {err}. Sending 500 response code...");
 
                 error::internal_server_error()
             }
         }
     } else {
         error!(
-            "{MISSING_APP_DATA}. BackendVars: {:?}",
-            req.app_data::<BackendVars>(),
+            "{MISSING_APP_DATA}. SQLite Connection Pool: {:?}; LoginLimiter: {:?}; Credential backend set: {}; Token mode set: {}",
+            req.app_data::<SqlitePool>(),
+            req.app_data::<LoginLimiter>(),
+            req.app_data::<SharedCredentialBackend>().is_some(),
+            req.app_data::<TokenMode>().is_some(),
         );
 
         error::internal_server_error()
     }
 }
 
+/// The second login step for users with TOTP enabled. Answers the challenge from ``login`` with a TOTP code.
+#[post("/mfa")]
+async fn login_mfa(req: HttpRequest, mfa_login: Json<MfaLogin>) -> HttpResponse {
+    let (pool, limiter, token_mode) = match (
+        req.app_data::<SqlitePool>(),
+        req.app_data::<LoginLimiter>(),
+        req.app_data::<TokenMode>(),
+    ) {
+        (Some(pool), Some(limiter), Some(token_mode)) => (pool, limiter, token_mode),
+        _ => {
+            error!(
+                "{MISSING_APP_DATA}. SQLite Connection Pool: {:?}; LoginLimiter: {:?}; Token mode set: {}",
+                req.app_data::<SqlitePool>(),
+                req.app_data::<LoginLimiter>(),
+                req.app_data::<TokenMode>().is_some(),
+            );
+
+            return error::internal_server_error();
+        }
+    };
+    let peer_addr = match listener::client_addr(&req) {
+        Some(addr) => addr,
+        None => return listener::unknown_client(),
+    };
+    let (username, role) = match session::find_mfa_challenge(pool, &mfa_login.challenge).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            security_log::auth_failure(Some(&peer_addr), None, AuthFailureReason::InvalidToken);
+
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Bad or expired challenge.".to_string(),
+            });
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while looking up MFA challenge: {err}");
+
+            return error::internal_server_error();
+        }
+    };
+
+    if let Err(retry_after) = limiter.check(&peer_addr, &username) {
+        security_log::auth_failure(
+            Some(&peer_addr),
+            Some(&username),
+            AuthFailureReason::RateLimited,
+        );
+
+        return too_many_attempts(retry_after);
+    }
+
+    match users::verify_totp(pool, &username, &mfa_login.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            security_log::auth_failure(
+                Some(&peer_addr),
+                Some(&username),
+                AuthFailureReason::BadTotpCode,
+            );
+            limiter.record_failure(&username).await;
+
+            return HttpResponse::BadRequest().json(ErrorResponse {
+                error: "Bad credentials.".to_string(),
+            });
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while verifying TOTP code: {err}");
+
+            return error::internal_server_error();
+        }
+    }
+
+    limiter.record_success(&username).await;
+
+    let token = match session::delete_mfa_challenge(pool, &mfa_login.challenge).await {
+        Ok(()) => token_mode.issue(pool, &username, role).await,
+        Err(err) => Err(err.into()),
+    };
+
+    match token {
+        Ok(token) => authenticated_response(
+            &req,
+            Authentication {
+                is_valid: true,
+                token: Some(token),
+                role: Some(role),
+                mfa_required: false,
+                mfa_challenge: None,
+            },
+        ),
+        Err(err) => {
+            error!("Encountered error while issuing token: {err}. Sending 500 response code...");
+
+            error::internal_server_error()
+        }
+    }
+}
+
 pub(crate) fn login_endpoint_config(cfg: &mut ServiceConfig) {
     let json_cfg = JsonConfig::default()
         .limit(MAX_USERNAME_LEN + MAX_PASSWORD_LEN + BUFFER_SPACE)
         .content_type(|mime_type| mime_type == mime::APPLICATION_JSON);
 
-    cfg.service(login).app_data(json_cfg);
+    cfg.service(login).service(login_mfa).app_data(json_cfg);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{
+        cookie::SameSite,
+        http::StatusCode,
+        test::{call_service, init_service, read_body_json, TestRequest},
+        web, App,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{credentials::SqliteBackend, db, env_vars::BackendVars, password};
+
+    fn retry_after_of(retry_after: Duration) -> String {
+        let res = too_many_attempts(retry_after);
+
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        res.headers()
+            .get(header::RETRY_AFTER)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn retry_after_rounds_up_to_whole_seconds() {
+        assert_eq!(retry_after_of(Duration::from_secs(900)), "900");
+        assert_eq!(retry_after_of(Duration::from_millis(59_001)), "60");
+        assert_eq!(retry_after_of(Duration::ZERO), "1");
+    }
+
+    #[actix_web::test]
+    async fn cookie_login_sets_http_only_strict_cookies() {
+        let pool = db::memory_pool().await;
+        let hash = password::hash_password("long enough").await.unwrap();
+
+        users::create_user(&pool, "admin", &hash, Role::Admin)
+            .await
+            .unwrap();
+
+        let vars = BackendVars {
+            auth_cookies: Some(true),
+            ..BackendVars::for_tests()
+        };
+        let limiter = LoginLimiter::new(&pool, &vars).await.unwrap();
+        let backend: SharedCredentialBackend = Arc::new(SqliteBackend);
+        let app = init_service(
+            App::new()
+                .app_data(vars)
+                .app_data(limiter)
+                .app_data(backend)
+                .app_data(TokenMode::Session)
+                .app_data(pool)
+                .service(web::scope("/login").configure(login_endpoint_config)),
+        )
+        .await;
+        let req = TestRequest::post()
+            .uri("/login")
+            .peer_addr("10.0.0.1:4000".parse().unwrap())
+            .set_json(json!({ "username": "admin", "password": "long enough" }))
+            .to_request();
+        let res = call_service(&app, req).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let cookies: Vec<_> = res.response().cookies().collect();
+        let session = cookies.iter().find(|c| c.name() == "session").unwrap();
+        let csrf = cookies.iter().find(|c| c.name() == "csrf_token").unwrap();
+
+        assert_eq!(session.http_only(), Some(true));
+        assert_eq!(session.secure(), Some(true));
+        assert_eq!(session.same_site(), Some(SameSite::Strict));
+        // The CSRF token has to be readable by JS, so it can be echoed in a header.
+        assert_ne!(csrf.http_only(), Some(true));
+        assert_eq!(csrf.secure(), Some(true));
+        assert_eq!(csrf.same_site(), Some(SameSite::Strict));
+
+        let body: Value = read_body_json(res).await;
+
+        assert_eq!(body["token"], Value::Null);
+    }
 }
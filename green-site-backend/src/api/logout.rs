@@ -0,0 +1,87 @@
+use actix_web::{post, web::ServiceConfig, HttpRequest, HttpResponse, Responder};
+use log::{error, info};
+
+use sqlx::SqlitePool;
+
+use crate::{
+    error::{self, MISSING_APP_DATA},
+    listener,
+    security_log::{self, AuthFailureReason},
+    session,
+    token::{self, authenticated_session, request_token, SessionToken, TokenMode},
+};
+
+/// Responds that the caller logged out, removing the session cookie if ``AUTH_COOKIES`` is enabled.
+fn logged_out(req: &HttpRequest) -> HttpResponse {
+    let mut res = HttpResponse::Ok().finish();
+
+    if token::auth_cookies_enabled(req) {
+        let cookie = token::session_cookie(&SessionToken::new(String::new()));
+
+        if let Err(err) = res.add_removal_cookie(&cookie) {
+            error!("Couldn't remove session cookie: {err}");
+        }
+    }
+
+    res
+}
+
+#[post("")]
+async fn logout(req: HttpRequest) -> impl Responder {
+    let (pool, token_mode) = match (req.app_data::<SqlitePool>(), req.app_data::<TokenMode>()) {
+        (Some(pool), Some(token_mode)) => (pool, token_mode),
+        _ => {
+            error!(
+                "{MISSING_APP_DATA}. SQLite Connection Pool: {:?}; Token mode set: {}",
+                req.app_data::<SqlitePool>(),
+                req.app_data::<TokenMode>().is_some(),
+            );
+
+            return error::internal_server_error();
+        }
+    };
+
+    // JWTs can't be revoked, so the client discarding it is all logging out does.
+    if let TokenMode::Jwt(_) = token_mode {
+        return match authenticated_session(&req).await {
+            Some(session) => {
+                info!("{} logged out", session.username);
+
+                logged_out(&req)
+            }
+            None => HttpResponse::Unauthorized().finish(),
+        };
+    }
+
+    let peer_addr = listener::client_addr(&req);
+    let token = match request_token(&req) {
+        Some(token) => token,
+        None => {
+            security_log::auth_failure(peer_addr.as_deref(), None, AuthFailureReason::MissingToken);
+
+            return HttpResponse::Unauthorized().finish();
+        }
+    };
+
+    match session::revoke_token(pool, &token).await {
+        Ok(true) => {
+            info!("{peer_addr:?} logged out");
+
+            logged_out(&req)
+        }
+        Ok(false) => {
+            security_log::auth_failure(peer_addr.as_deref(), None, AuthFailureReason::InvalidToken);
+
+            HttpResponse::Unauthorized().finish()
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while revoking session: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+pub(crate) fn logout_endpoint_config(cfg: &mut ServiceConfig) {
+    cfg.service(logout);
+}
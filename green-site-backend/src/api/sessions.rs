@@ -0,0 +1,101 @@
+use actix_web::{
+    delete, get,
+    web::{Path, ServiceConfig},
+    HttpRequest, HttpResponse, Responder,
+};
+use log::{error, warn};
+
+use sqlx::SqlitePool;
+
+use crate::{
+    error::{self, ErrorResponse, MISSING_APP_DATA},
+    listener,
+    role::Permission,
+    session, verify_permission,
+};
+
+macro_rules! verify_pool {
+    ($req:ident) => {
+        match $req.app_data::<SqlitePool>() {
+            Some(pool) => pool,
+            None => {
+                error!("{MISSING_APP_DATA}. SQLite Connection Pool: None");
+
+                return error::internal_server_error();
+            }
+        }
+    };
+}
+
+#[get("")]
+async fn get_sessions(req: HttpRequest) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageSessions);
+
+    match session::list_sessions(pool).await {
+        Ok(sessions) => HttpResponse::Ok().json(sessions),
+        Err(err) => {
+            error!("Encountered sqlx error while listing sessions: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+#[delete("")]
+async fn delete_sessions(req: HttpRequest) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageSessions);
+
+    match session::revoke_all_sessions(pool).await {
+        Ok(count) => {
+            warn!(
+                "{:?} revoked all {count} sessions",
+                listener::client_addr(&req)
+            );
+
+            HttpResponse::Ok().finish()
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while revoking all sessions: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+#[delete("/{session_id}")]
+async fn delete_session_by_id(req: HttpRequest, path: Path<i64>) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageSessions);
+
+    let session_id = path.into_inner();
+
+    match session::revoke_session(pool, session_id).await {
+        Ok(true) => {
+            warn!(
+                "{:?} revoked session {session_id}",
+                listener::client_addr(&req)
+            );
+
+            HttpResponse::Ok().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().json(ErrorResponse {
+            error: "Couldn't find requested session by ID".to_string(),
+        }),
+        Err(err) => {
+            error!("Encountered sqlx error while revoking session: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+pub(crate) fn session_endpoint_config(cfg: &mut ServiceConfig) {
+    cfg.service(get_sessions)
+        .service(delete_sessions)
+        .service(delete_session_by_id);
+}
@@ -5,6 +5,8 @@ use sqlx::{FromRow, MySqlPool};
 
 use crate::env_vars::BackendVars;
 use crate::error::{self, MISSING_APP_DATA};
+use crate::role::Permission;
+use crate::verify_permission;
 
 #[derive(FromRow, Serialize)]
 struct SolarPanelInfo {
@@ -46,6 +48,8 @@ async fn get_solar_panel_info(
 #[get("")]
 async fn get_solar_data(req: HttpRequest) -> impl Responder {
     if let (Some(pool), Some(vars)) = (req.app_data::<MySqlPool>(), req.app_data::<BackendVars>()) {
+        verify_permission!(req, Permission::ReadSolar);
+
         match get_solar_panel_info(pool, vars).await {
             Ok(info) => HttpResponse::Ok().json(info),
             Err(err) => {
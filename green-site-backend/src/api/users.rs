@@ -0,0 +1,488 @@
+use actix_web::{
+    delete,
+    error::InternalError,
+    get, post, put,
+    web::{Json, JsonConfig, Path, ServiceConfig},
+    HttpRequest, HttpResponse, Responder,
+};
+use log::{error, warn};
+use serde::Deserialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    env_vars::BackendVars,
+    error::{self, ErrorResponse, MISSING_APP_DATA},
+    listener,
+    password::{self, StrengthPolicy},
+    role::{Permission, Role},
+    session, users, verify_permission,
+};
+
+use super::login::{
+    password_error, username_error, BUFFER_SPACE, MAX_PASSWORD_LEN, MAX_USERNAME_LEN,
+};
+
+#[derive(Deserialize)]
+struct NewUser {
+    username: String,
+    password: String,
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct PasswordUpdate {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct RoleUpdate {
+    role: Role,
+}
+
+#[derive(Deserialize)]
+struct DisabledUpdate {
+    disabled: bool,
+}
+
+macro_rules! verify_pool {
+    ($req:ident) => {
+        match $req.app_data::<SqlitePool>() {
+            Some(pool) => pool,
+            None => {
+                error!("{MISSING_APP_DATA}. SQLite Connection Pool: None");
+
+                return error::internal_server_error();
+            }
+        }
+    };
+}
+
+macro_rules! verify_username {
+    ($username:expr) => {
+        if let Some(err) = username_error($username) {
+            return HttpResponse::BadRequest().json(err);
+        }
+    };
+}
+
+/// Checks the new password of the user against the length limits and the strength policy.
+fn new_password_error(req: &HttpRequest, username: &str, password: &str) -> Option<ErrorResponse> {
+    password_error(password).or_else(|| {
+        req.app_data::<BackendVars>()
+            .and_then(|vars| StrengthPolicy::new(vars).check(username, password))
+            .map(|error| ErrorResponse { error })
+    })
+}
+
+fn user_not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(ErrorResponse {
+        error: "Couldn't find requested user".to_string(),
+    })
+}
+
+/// Revokes the user's sessions after their credentials or access changed, responding with the outcome of the change.
+/// A change that didn't apply to an existing user was refused for leaving no enabled admins.
+async fn finish_update(
+    req: &HttpRequest,
+    pool: &SqlitePool,
+    username: &str,
+    action: &str,
+    updated: sqlx::Result<bool>,
+) -> HttpResponse {
+    match updated {
+        Ok(true) => {
+            warn!("{:?} {action} user {username}", listener::client_addr(req));
+
+            match session::revoke_user_sessions(pool, username).await {
+                Ok(_) => HttpResponse::Ok().finish(),
+                Err(err) => {
+                    error!("Encountered sqlx error while revoking sessions of {username}: {err}");
+
+                    error::internal_server_error()
+                }
+            }
+        }
+        Ok(false) => match users::user_exists(pool, username).await {
+            Ok(true) => HttpResponse::Conflict().json(ErrorResponse {
+                error: "The change would leave no enabled admins".to_string(),
+            }),
+            Ok(false) => user_not_found(),
+            Err(err) => {
+                error!("Encountered sqlx error while looking up user {username}: {err}");
+
+                error::internal_server_error()
+            }
+        },
+        Err(err) => {
+            error!("Encountered sqlx error while updating user {username}: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+#[get("")]
+async fn get_users(req: HttpRequest) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageUsers);
+
+    match users::list_users(pool).await {
+        Ok(users) => HttpResponse::Ok().json(users),
+        Err(err) => {
+            error!("Encountered sqlx error while listing users: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+#[post("")]
+async fn create_user(req: HttpRequest, new_user: Json<NewUser>) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageUsers);
+    verify_username!(&new_user.username);
+
+    if let Some(err) = new_password_error(&req, &new_user.username, &new_user.password) {
+        return HttpResponse::BadRequest().json(err);
+    }
+
+    let password_hash = match password::hash_password(&new_user.password).await {
+        Ok(hash) => hash,
+        Err(err) => {
+            error!(
+                "Couldn't hash password of new user {}: {err}",
+                new_user.username
+            );
+
+            return error::internal_server_error();
+        }
+    };
+
+    match users::create_user(pool, &new_user.username, &password_hash, new_user.role).await {
+        Ok(true) => {
+            warn!(
+                "{:?} created {:?} user {}",
+                listener::client_addr(&req),
+                new_user.role,
+                new_user.username,
+            );
+
+            HttpResponse::Created().finish()
+        }
+        Ok(false) => HttpResponse::Conflict().json(ErrorResponse {
+            error: "A user with that username already exists".to_string(),
+        }),
+        Err(err) => {
+            error!("Encountered sqlx error while creating user: {err}");
+
+            error::internal_server_error()
+        }
+    }
+}
+
+#[put("/{username}/password")]
+async fn update_password(
+    req: HttpRequest,
+    path: Path<String>,
+    update: Json<PasswordUpdate>,
+) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageUsers);
+
+    let username = path.into_inner();
+
+    verify_username!(&username);
+
+    if let Some(err) = new_password_error(&req, &username, &update.password) {
+        return HttpResponse::BadRequest().json(err);
+    }
+
+    let password_hash = match password::hash_password(&update.password).await {
+        Ok(hash) => hash,
+        Err(err) => {
+            error!("Couldn't hash new password of {username}: {err}");
+
+            return error::internal_server_error();
+        }
+    };
+    let updated = users::set_password(pool, &username, &password_hash).await;
+
+    finish_update(&req, pool, &username, "changed the password of", updated).await
+}
+
+#[put("/{username}/role")]
+async fn update_role(
+    req: HttpRequest,
+    path: Path<String>,
+    update: Json<RoleUpdate>,
+) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageUsers);
+
+    let username = path.into_inner();
+
+    verify_username!(&username);
+
+    let updated = users::set_role(pool, &username, update.role).await;
+
+    finish_update(&req, pool, &username, "changed the role of", updated).await
+}
+
+#[put("/{username}/disabled")]
+async fn update_disabled(
+    req: HttpRequest,
+    path: Path<String>,
+    update: Json<DisabledUpdate>,
+) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageUsers);
+
+    let username = path.into_inner();
+
+    verify_username!(&username);
+
+    let updated = users::set_disabled(pool, &username, update.disabled).await;
+    let action = match update.disabled {
+        true => "disabled",
+        false => "enabled",
+    };
+
+    finish_update(&req, pool, &username, action, updated).await
+}
+
+#[delete("/{username}")]
+async fn delete_user(req: HttpRequest, path: Path<String>) -> impl Responder {
+    let pool = verify_pool!(req);
+
+    verify_permission!(req, Permission::ManageUsers);
+
+    let username = path.into_inner();
+
+    verify_username!(&username);
+
+    let deleted = users::delete_user(pool, &username).await;
+
+    finish_update(&req, pool, &username, "deleted", deleted).await
+}
+
+pub(crate) fn user_endpoint_config(cfg: &mut ServiceConfig) {
+    let json_cfg = JsonConfig::default()
+        .limit(MAX_USERNAME_LEN + MAX_PASSWORD_LEN + BUFFER_SPACE)
+        .content_type(|mime_type| mime_type == mime::APPLICATION_JSON)
+        .error_handler(|err, _| {
+            let res = HttpResponse::BadRequest().json(ErrorResponse {
+                error: err.to_string(),
+            });
+
+            InternalError::from_response(err, res).into()
+        });
+
+    cfg.service(get_users)
+        .service(create_user)
+        .service(update_password)
+        .service(update_role)
+        .service(update_disabled)
+        .service(delete_user)
+        .app_data(json_cfg);
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        dev::{ServiceFactory, ServiceRequest, ServiceResponse},
+        http::{header, StatusCode},
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::{db, token::TokenMode};
+
+    /// A DB with an enabled admin named ``admin`` and that admin's session token.
+    async fn admin_session() -> (SqlitePool, String) {
+        let pool = db::memory_pool().await;
+
+        users::create_user(&pool, "admin", "unused", Role::Admin)
+            .await
+            .unwrap();
+
+        let token = session::create_session(&pool, "admin").await.unwrap();
+
+        (pool, token.as_str().to_string())
+    }
+
+    fn users_app(
+        pool: &SqlitePool,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse,
+            Error = actix_web::Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .app_data(BackendVars::for_tests())
+            .app_data(pool.clone())
+            .app_data(TokenMode::Session)
+            .service(web::scope("/users").configure(user_endpoint_config))
+    }
+
+    fn request(req: TestRequest, token: &str, uri: &str, body: Option<Value>) -> TestRequest {
+        let req = req
+            .uri(uri)
+            .insert_header((header::AUTHORIZATION, format!("Bearer {token}")));
+
+        match body {
+            Some(body) => req.set_json(body),
+            None => req,
+        }
+    }
+
+    #[actix_web::test]
+    async fn refuses_to_remove_the_last_enabled_admin() {
+        let (pool, token) = admin_session().await;
+        let app = init_service(users_app(&pool)).await;
+
+        for (req, uri, body) in [
+            (
+                TestRequest::put(),
+                "/users/admin/role",
+                Some(json!({ "role": "operator" })),
+            ),
+            (
+                TestRequest::put(),
+                "/users/admin/disabled",
+                Some(json!({ "disabled": true })),
+            ),
+            (TestRequest::delete(), "/users/admin", None),
+        ] {
+            let res = call_service(&app, request(req, &token, uri, body).to_request()).await;
+
+            assert_eq!(res.status(), StatusCode::CONFLICT, "{uri}");
+        }
+
+        assert_eq!(
+            users::login_account(&pool, "admin").await.unwrap(),
+            Some((Role::Admin, false))
+        );
+
+        // Changes that keep an enabled admin are still made.
+        let body = Some(json!({ "disabled": false }));
+        let req = request(TestRequest::put(), &token, "/users/admin/disabled", body);
+
+        assert_eq!(
+            call_service(&app, req.to_request()).await.status(),
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn disabled_admins_dont_count() {
+        let (pool, token) = admin_session().await;
+        let app = init_service(users_app(&pool)).await;
+
+        users::create_user(&pool, "backup", "unused", Role::Admin)
+            .await
+            .unwrap();
+
+        for (uri, body, status) in [
+            (
+                "/users/backup/disabled",
+                json!({ "disabled": true }),
+                StatusCode::OK,
+            ),
+            (
+                "/users/admin/role",
+                json!({ "role": "viewer" }),
+                StatusCode::CONFLICT,
+            ),
+            (
+                "/users/backup/disabled",
+                json!({ "disabled": false }),
+                StatusCode::OK,
+            ),
+            (
+                "/users/admin/role",
+                json!({ "role": "viewer" }),
+                StatusCode::OK,
+            ),
+        ] {
+            let req = request(TestRequest::put(), &token, uri, Some(body));
+
+            assert_eq!(
+                call_service(&app, req.to_request()).await.status(),
+                status,
+                "{uri}"
+            );
+        }
+
+        assert_eq!(
+            users::login_account(&pool, "admin").await.unwrap(),
+            Some((Role::Viewer, false))
+        );
+    }
+
+    #[actix_web::test]
+    async fn deletes_admins_while_another_is_enabled() {
+        let (pool, token) = admin_session().await;
+        let app = init_service(users_app(&pool)).await;
+
+        users::create_user(&pool, "backup", "unused", Role::Admin)
+            .await
+            .unwrap();
+
+        for (uri, status) in [
+            ("/users/backup", StatusCode::OK),
+            ("/users/backup", StatusCode::NOT_FOUND),
+            ("/users/admin", StatusCode::CONFLICT),
+        ] {
+            let req = request(TestRequest::delete(), &token, uri, None);
+
+            assert_eq!(call_service(&app, req.to_request()).await.status(), status);
+        }
+    }
+
+    #[actix_web::test]
+    async fn validates_usernames_and_passwords_like_login() {
+        let (pool, token) = admin_session().await;
+        let app = init_service(users_app(&pool)).await;
+        let too_long = "a".repeat(MAX_USERNAME_LEN.max(MAX_PASSWORD_LEN) + 1);
+        let create = |username: &str, password: &str| {
+            let body = json!({ "username": username, "password": password, "role": "viewer" });
+
+            request(TestRequest::post(), &token, "/users", Some(body)).to_request()
+        };
+
+        for username in ["", "Mixed", "has space", "dígito", "user1", &too_long] {
+            let res = call_service(&app, create(username, "long enough")).await;
+
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{username:?}");
+        }
+
+        for password in ["", "short", "newuser", &too_long] {
+            let res = call_service(&app, create("newuser", password)).await;
+
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{password:?}");
+        }
+
+        let res = call_service(&app, create("newuser", "long enough")).await;
+
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let body = Some(json!({ "role": "viewer" }));
+        let req = request(TestRequest::put(), &token, "/users/Newuser/role", body);
+
+        assert_eq!(
+            call_service(&app, req.to_request()).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+}
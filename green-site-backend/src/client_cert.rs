@@ -0,0 +1,132 @@
+use std::{
+    any::Any,
+    fs::File,
+    io::BufReader,
+    sync::{Arc, RwLock},
+    time::SystemTime,
+};
+
+use actix_tls::accept::rustls::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream, HttpRequest};
+use log::{debug, error};
+use rustls::{
+    server::{AllowAnyAnonymousOrAuthenticatedClient, ClientCertVerified, ClientCertVerifier},
+    Certificate, DistinguishedNames, RootCertStore,
+};
+use sqlx::SqlitePool;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::{env_vars::BackendVars, error::CertConfigError, users};
+
+/// The verified certificate the client presented in the TLS handshake of the request's connection.
+#[derive(Clone)]
+struct ClientCertificate(Certificate);
+
+/// Whether ``REQUIRE_CLIENT_CERTS`` is enabled.
+pub(crate) fn client_certs_required(vars: &BackendVars) -> bool {
+    vars.require_client_certs.unwrap_or(false)
+}
+
+/// A verifier accepting client certificates signed by ``ROOT_CERTIFICATE_PATH``. Clients without one can still connect
+/// since only some endpoints require it.
+pub(crate) fn client_cert_verifier(
+    vars: &BackendVars,
+) -> Result<Arc<dyn ClientCertVerifier>, CertConfigError> {
+    let path = vars.root_certificate_path.as_str();
+    let file =
+        File::open(path).map_err(|e| CertConfigError::ReadPemIoError(path.to_string(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| CertConfigError::ReadPemIoError(path.to_string(), e))?;
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs);
+
+    if added == 0 {
+        return Err(CertConfigError::BadServerCertificate(format!(
+            "No usable root certificates in {path}"
+        )));
+    }
+
+    Ok(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+}
+
+/// Verifies client certificates with the current CA from ``ROOT_CERTIFICATE_PATH``. The verifier is swapped out when
+/// the TLS certificates are reloaded, so new handshakes use the new CA.
+pub(crate) struct ClientVerifier(RwLock<Arc<dyn ClientCertVerifier>>);
+
+impl ClientVerifier {
+    pub fn load(vars: &BackendVars) -> Result<Self, CertConfigError> {
+        Ok(Self(RwLock::new(client_cert_verifier(vars)?)))
+    }
+
+    pub fn replace(&self, verifier: Arc<dyn ClientCertVerifier>) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = verifier;
+    }
+
+    fn current(&self) -> Arc<dyn ClientCertVerifier> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl ClientCertVerifier for ClientVerifier {
+    fn offer_client_auth(&self) -> bool {
+        self.current().offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> Option<bool> {
+        self.current().client_auth_mandatory()
+    }
+
+    fn client_auth_root_subjects(&self) -> Option<DistinguishedNames> {
+        self.current().client_auth_root_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: SystemTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.current()
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
+
+/// Keeps the client certificate of each new HTTPS connection so requests on it can read it. Passed to
+/// ``HttpServer::on_connect``.
+pub(crate) fn store_client_certificate(conn: &dyn Any, data: &mut Extensions) {
+    if let Some(tls_stream) = conn.downcast_ref::<TlsStream<TcpStream>>() {
+        let (_, tls_conn) = tls_stream.get_ref();
+
+        if let Some(cert) = tls_conn.peer_certificates().and_then(|certs| certs.first()) {
+            data.insert(ClientCertificate(cert.clone()));
+        }
+    }
+}
+
+/// The common name of the certificate's subject.
+fn common_name(cert: &Certificate) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(&cert.0)
+        .map_err(|err| debug!("Couldn't parse client certificate: {err}"))
+        .ok()?;
+    let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(common_name.to_string())
+}
+
+/// The user the request's client certificate belongs to. The subject's common name must be the username of a user in
+/// the users table who isn't disabled.
+pub(crate) async fn certificate_user(req: &HttpRequest) -> Option<String> {
+    let cert = req.conn_data::<ClientCertificate>()?;
+    let username = common_name(&cert.0)?;
+    let pool = req.app_data::<SqlitePool>()?;
+
+    match users::login_account(pool, &username).await {
+        Ok(Some(_)) => Some(username),
+        Ok(None) => None,
+        Err(err) => {
+            error!("Encountered sqlx error while looking up client certificate user: {err}");
+
+            None
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::{io, sync::Arc, time::Duration};
+
+use futures::future::LocalBoxFuture;
+use ldap3::{LdapConnAsync, LdapConnSettings, LdapError};
+use log::{error, info, warn};
+use rand::{distributions::Alphanumeric, rngs::OsRng, Rng};
+use sqlx::SqlitePool;
+
+use crate::{
+    env_vars::BackendVars,
+    error::CredentialError,
+    password::{self, Verification},
+    role::Role,
+    tls::TrustedRoots,
+    users,
+};
+
+const LDAP_TIMEOUT: Duration = Duration::from_secs(5);
+/// The LDAP result code for a failed bind because of a bad DN or password.
+const LDAP_INVALID_CREDENTIALS: u32 = 49;
+/// Replaced by the username in ``LDAP_USER_DN_TEMPLATE``.
+const USERNAME_PLACEHOLDER: &str = "{username}";
+
+/// Where user passwords are checked. Roles, TOTP and whether a user is disabled are always kept in the SQLite users
+/// table, whatever the backend is.
+pub(crate) trait CredentialBackend: Send + Sync {
+    /// Checks the password of the user. Must never return true if the credentials are invalid.
+    fn verify<'a>(
+        &'a self,
+        pool: &'a SqlitePool,
+        username: &'a str,
+        password: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, CredentialError>>;
+
+    /// Whether passwords are stored by this backend, so users can change them through the API.
+    fn stores_passwords(&self) -> bool {
+        false
+    }
+}
+
+/// The credential backend used by every worker, picked by ``CREDENTIAL_BACKEND``.
+pub(crate) type SharedCredentialBackend = Arc<dyn CredentialBackend>;
+
+/// Checks passwords against the Argon2id hashes in the users table.
+pub(crate) struct SqliteBackend;
+
+impl CredentialBackend for SqliteBackend {
+    fn verify<'a>(
+        &'a self,
+        pool: &'a SqlitePool,
+        username: &'a str,
+        password: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, CredentialError>> {
+        Box::pin(async move {
+            let verification = match users::stored_password(pool, username).await? {
+                Some(stored) => password::verify_password(password, &stored).await?,
+                None => {
+                    // Unknown users still pay for a hash, so response times don't reveal which usernames exist.
+                    password::verify_dummy_password(password).await?;
+
+                    Verification::Invalid
+                }
+            };
+
+            // Legacy plaintext passwords are re-hashed with Argon2id on the first successful login.
+            if verification == Verification::ValidLegacy {
+                match password::hash_password(password).await {
+                    Ok(hash) => {
+                        users::set_password(pool, username, &hash).await?;
+                    }
+                    Err(err) => error!("Couldn't re-hash legacy password for {username}: {err}"),
+                }
+            }
+
+            Ok(verification != Verification::Invalid)
+        })
+    }
+
+    fn stores_passwords(&self) -> bool {
+        true
+    }
+}
+
+/// Checks passwords with a simple bind to an LDAP directory over StartTLS. Users that bind successfully for the first
+/// time are added to the users table as viewers, with a random password that's never given out.
+pub(crate) struct LdapBackend {
+    url: String,
+    user_dn_template: String,
+    roots: TrustedRoots,
+}
+
+impl LdapBackend {
+    pub fn new(url: String, user_dn_template: String, roots: TrustedRoots) -> io::Result<Self> {
+        if !user_dn_template.contains(USERNAME_PLACEHOLDER) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("LDAP_USER_DN_TEMPLATE must contain {USERNAME_PLACEHOLDER}"),
+            ));
+        }
+
+        Ok(Self {
+            url,
+            user_dn_template,
+            roots,
+        })
+    }
+
+    fn user_dn(&self, username: &str) -> String {
+        self.user_dn_template
+            .replace(USERNAME_PLACEHOLDER, &ldap3::dn_escape(username))
+    }
+
+    async fn bind(&self, username: &str, password: &str) -> Result<bool, CredentialError> {
+        // Taken for every bind so a reloaded root certificate is picked up.
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(LDAP_TIMEOUT)
+            .set_starttls(true)
+            .set_config(self.roots.ldap_config());
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.url).await?;
+
+        ldap3::drive!(conn);
+
+        let result = ldap
+            .with_timeout(LDAP_TIMEOUT)
+            .simple_bind(&self.user_dn(username), password)
+            .await?;
+
+        if let Err(err) = ldap.unbind().await {
+            warn!("Couldn't unbind from LDAP server: {err}");
+        }
+
+        match result.rc {
+            0 => Ok(true),
+            LDAP_INVALID_CREDENTIALS => Ok(false),
+            _ => Err(LdapError::LdapResult { result }.into()),
+        }
+    }
+}
+
+impl CredentialBackend for LdapBackend {
+    fn verify<'a>(
+        &'a self,
+        pool: &'a SqlitePool,
+        username: &'a str,
+        password: &'a str,
+    ) -> LocalBoxFuture<'a, Result<bool, CredentialError>> {
+        Box::pin(async move {
+            // A simple bind with an empty password is an unauthenticated bind, which always succeeds.
+            if password.is_empty() || !self.bind(username, password).await? {
+                return Ok(false);
+            }
+
+            let unusable_password: String = OsRng
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect();
+            let password_hash = password::hash_password(&unusable_password).await?;
+
+            if users::create_user(pool, username, &password_hash, Role::Viewer).await? {
+                info!("Added directory user {username} as a viewer");
+            }
+
+            Ok(true)
+        })
+    }
+}
+
+/// Creates the backend set by ``CREDENTIAL_BACKEND``, which is ``sqlite`` (default) or ``ldap``.
+pub(crate) fn from_vars(
+    vars: &BackendVars,
+    roots: &TrustedRoots,
+) -> io::Result<SharedCredentialBackend> {
+    match vars.credential_backend.as_deref() {
+        None | Some("sqlite") => Ok(Arc::new(SqliteBackend)),
+        Some("ldap") => match (&vars.ldap_url, &vars.ldap_user_dn_template) {
+            (Some(url), Some(user_dn_template)) => {
+                info!("Checking credentials against LDAP server {url}");
+
+                Ok(Arc::new(LdapBackend::new(
+                    url.clone(),
+                    user_dn_template.clone(),
+                    roots.clone(),
+                )?))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "CREDENTIAL_BACKEND=ldap requires LDAP_URL and LDAP_USER_DN_TEMPLATE",
+            )),
+        },
+        Some(backend) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Bad CREDENTIAL_BACKEND {backend:?}. Expected sqlite or ldap"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    #[actix_web::test]
+    async fn rehashes_legacy_plaintext_on_first_login() {
+        let pool = db::memory_pool().await;
+
+        users::create_user(&pool, "legacy", "hunter22", Role::Viewer)
+            .await
+            .unwrap();
+
+        assert!(!SqliteBackend
+            .verify(&pool, "legacy", "hunter23")
+            .await
+            .unwrap());
+        assert_eq!(
+            users::stored_password(&pool, "legacy").await.unwrap(),
+            Some("hunter22".to_string())
+        );
+
+        assert!(SqliteBackend
+            .verify(&pool, "legacy", "hunter22")
+            .await
+            .unwrap());
+
+        let stored = users::stored_password(&pool, "legacy")
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(SqliteBackend
+            .verify(&pool, "legacy", "hunter22")
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn rejects_wrong_password_against_hash() {
+        let pool = db::memory_pool().await;
+        let hash = password::hash_password("correct horse").await.unwrap();
+
+        users::create_user(&pool, "viewer", &hash, Role::Viewer)
+            .await
+            .unwrap();
+
+        assert!(!SqliteBackend
+            .verify(&pool, "viewer", "wrong horse")
+            .await
+            .unwrap());
+        assert!(!SqliteBackend.verify(&pool, "viewer", &hash).await.unwrap());
+        assert!(SqliteBackend
+            .verify(&pool, "viewer", "correct horse")
+            .await
+            .unwrap());
+    }
+
+    #[actix_web::test]
+    async fn hashes_for_unknown_and_disabled_users() {
+        let pool = db::memory_pool().await;
+        let hash = password::hash_password("correct horse").await.unwrap();
+
+        users::create_user(&pool, "gone", &hash, Role::Viewer)
+            .await
+            .unwrap();
+        users::set_disabled(&pool, "gone", true).await.unwrap();
+
+        assert!(!SqliteBackend
+            .verify(&pool, "nobody", "correct horse")
+            .await
+            .unwrap());
+        assert!(!SqliteBackend
+            .verify(&pool, "gone", "correct horse")
+            .await
+            .unwrap());
+        assert!(!users::user_exists(&pool, "nobody").await.unwrap());
+    }
+}
@@ -0,0 +1,265 @@
+use actix_web::{
+    body::EitherBody,
+    cookie::{Cookie, SameSite},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::Method,
+    Error, HttpResponse,
+};
+use futures::future::{self, LocalBoxFuture, Ready};
+use log::error;
+use subtle::ConstantTimeEq;
+
+use crate::{
+    error::ErrorResponse,
+    listener,
+    security_log::{self, AuthFailureReason},
+    session, token,
+};
+
+/// The cookie the frontend reads the CSRF token from. Unlike the session cookie, it's readable by JS.
+const CSRF_COOKIE: &str = "csrf_token";
+/// The header the frontend echoes the CSRF token back in.
+const CSRF_HEADER: &str = "X-CSRF-Token";
+/// Logging in issues a new CSRF token, so there may not be one yet. Matched exactly, after trailing slashes are trimmed.
+const EXEMPT_PATHS: [&str; 2] = ["/api/login", "/api/login/mfa"];
+
+/// A new random CSRF token as a cookie.
+pub(crate) fn csrf_cookie() -> Cookie<'static> {
+    Cookie::build(CSRF_COOKIE, session::random_token())
+        .path("/")
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+/// Checks that the CSRF header matches the CSRF cookie in constant time.
+fn has_valid_csrf_token(req: &ServiceRequest) -> bool {
+    let cookie = req.cookie(CSRF_COOKIE);
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok());
+
+    match (cookie, header) {
+        (Some(cookie), Some(header)) if !cookie.value().is_empty() => {
+            bool::from(cookie.value().as_bytes().ct_eq(header.as_bytes()))
+        }
+        _ => false,
+    }
+}
+
+/// Middleware requiring a double-submit CSRF token on state-changing requests when ``AUTH_COOKIES`` is enabled.
+/// Requests with a ``Bearer`` token are exempt since browsers never attach one cross-site and the session cookie is
+/// then ignored. Any other Authorization header doesn't exempt the request. A CSRF cookie is issued on any response
+/// to a request that didn't have one.
+#[derive(Clone)]
+pub(crate) struct CsrfProtection;
+
+impl<S, B> Transform<S, ServiceRequest> for CsrfProtection
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CsrfProtectionMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(CsrfProtectionMiddleware { service }))
+    }
+}
+
+pub(crate) struct CsrfProtectionMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfProtectionMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let is_state_changing =
+            !matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+        let needs_check = is_state_changing
+            && token::bearer_token(req.request()).is_none()
+            && !EXEMPT_PATHS.contains(&req.path());
+
+        if needs_check && !has_valid_csrf_token(&req) {
+            security_log::auth_failure(
+                listener::client_addr(req.request()).as_deref(),
+                None,
+                AuthFailureReason::BadCsrfToken,
+            );
+
+            let res = HttpResponse::Forbidden().json(ErrorResponse {
+                error: format!("Missing or bad {CSRF_HEADER} header."),
+            });
+
+            return Box::pin(future::ready(Ok(req
+                .into_response(res)
+                .map_into_right_body())));
+        }
+
+        let has_csrf_cookie = req.cookie(CSRF_COOKIE).is_some();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let mut res = fut.await?;
+            let sets_csrf_cookie = res
+                .response()
+                .cookies()
+                .any(|cookie| cookie.name() == CSRF_COOKIE);
+
+            if !has_csrf_cookie && !sets_csrf_cookie {
+                if let Err(err) = res.response_mut().add_cookie(&csrf_cookie()) {
+                    error!("Couldn't add CSRF cookie: {err}");
+                }
+            }
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        cookie::Cookie,
+        http::{header, StatusCode},
+        test::{call_service, init_service, TestRequest},
+        web, App,
+    };
+
+    use super::*;
+
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    /// The status of the request to a protected app where every route responds with 200.
+    async fn status_of(req: TestRequest) -> StatusCode {
+        let app = init_service(
+            App::new().service(
+                web::scope("/api")
+                    .wrap(CsrfProtection)
+                    .route("/login", web::post().to(ok))
+                    .route("/login/mfa", web::post().to(ok))
+                    .route("/loginanything", web::post().to(ok))
+                    .route("/users", web::get().to(ok))
+                    .route("/users", web::post().to(ok)),
+            ),
+        )
+        .await;
+
+        call_service(
+            &app,
+            req.peer_addr("10.0.0.1:4000".parse().unwrap()).to_request(),
+        )
+        .await
+        .status()
+    }
+
+    fn with_csrf(req: TestRequest, cookie: &str, header: &str) -> TestRequest {
+        req.cookie(Cookie::new(CSRF_COOKIE, cookie.to_string()))
+            .insert_header((CSRF_HEADER, header))
+    }
+
+    #[actix_web::test]
+    async fn requires_matching_token_on_state_changing_requests() {
+        let post = || TestRequest::post().uri("/api/users");
+
+        assert_eq!(status_of(post()).await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            status_of(post().cookie(Cookie::new(CSRF_COOKIE, "abc"))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_of(post().insert_header((CSRF_HEADER, "abc"))).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_of(with_csrf(post(), "abc", "abd")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_of(with_csrf(post(), "", "")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status_of(with_csrf(post(), "abc", "abc")).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_of(TestRequest::get().uri("/api/users")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn only_bearer_tokens_bypass_the_check() {
+        let post = || TestRequest::post().uri("/api/users");
+
+        assert_eq!(
+            status_of(post().insert_header((header::AUTHORIZATION, "Bearer token"))).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status_of(post().insert_header((header::AUTHORIZATION, "Basic dXNlcjpwYXNz"))).await,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[actix_web::test]
+    async fn exempts_only_the_login_paths() {
+        for (uri, status) in [
+            ("/api/login", StatusCode::OK),
+            ("/api/login/mfa", StatusCode::OK),
+            ("/api/loginanything", StatusCode::FORBIDDEN),
+        ] {
+            assert_eq!(
+                status_of(TestRequest::post().uri(uri)).await,
+                status,
+                "{uri}"
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn issues_a_csrf_cookie_when_missing() {
+        let app = init_service(
+            App::new().service(
+                web::scope("/api")
+                    .wrap(CsrfProtection)
+                    .route("/users", web::get().to(ok)),
+            ),
+        )
+        .await;
+        let res = call_service(&app, TestRequest::get().uri("/api/users").to_request()).await;
+        let cookie = res
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == CSRF_COOKIE)
+            .unwrap();
+
+        assert!(!cookie.value().is_empty());
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Strict));
+        assert_eq!(cookie.http_only(), None);
+
+        let req = with_csrf(TestRequest::get(), "abc", "abc").uri("/api/users");
+        let res = call_service(&app, req.to_request()).await;
+
+        assert_eq!(res.response().cookies().count(), 0);
+    }
+}
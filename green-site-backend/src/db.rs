@@ -0,0 +1,105 @@
+use std::{error::Error, str::FromStr, time::Duration};
+
+use log::info;
+use sqlx::{
+    migrate::Migrator,
+    pool::PoolOptions,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode},
+    SqlitePool,
+};
+
+use crate::{env_vars::BackendVars, password};
+
+/// The SQLite auth DB migrations in ``migrations/``, embedded into the binary.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// The migration adding the ``role`` column to the users table.
+const ROLES_MIGRATION: i64 = 20221101000003;
+
+/// Connects to the SQLite auth DB, creating the file if it doesn't exist.
+pub(crate) async fn create_sqlite_pool(vars: &BackendVars) -> sqlx::Result<SqlitePool> {
+    let conn_options = SqliteConnectOptions::from_str(&vars.sqlite_file_name)?
+        .create_if_missing(true)
+        .journal_mode(SqliteJournalMode::Wal)
+        .busy_timeout(Duration::from_secs(5));
+
+    PoolOptions::new()
+        .max_connections(8)
+        .acquire_timeout(Duration::from_secs(3))
+        .connect_with(conn_options)
+        .await
+}
+
+async fn is_migration_applied(pool: &SqlitePool, version: i64) -> sqlx::Result<bool> {
+    let (has_table,): (bool,) = sqlx::query_as(
+        "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name='_sqlx_migrations';",
+    )
+    .fetch_one(pool)
+    .await?;
+
+    if !has_table {
+        return Ok(false);
+    }
+
+    let (is_applied,): (bool,) =
+        sqlx::query_as("SELECT COUNT(*) > 0 FROM _sqlx_migrations WHERE version=? AND success;")
+            .bind(version)
+            .fetch_one(pool)
+            .await?;
+
+    Ok(is_applied)
+}
+
+/// Runs any pending migrations. When the roles migration is first applied, the user named by
+/// ``ADMIN_ACCOUNT_USERNAME`` becomes an admin. If that user doesn't exist and ``ADMIN_ACCOUNT_PASSWORD``
+/// is set, it's created so a fresh DB can be logged into.
+pub(crate) async fn run_migrations(
+    pool: &SqlitePool,
+    vars: &BackendVars,
+) -> Result<(), Box<dyn Error>> {
+    let had_roles = is_migration_applied(pool, ROLES_MIGRATION).await?;
+
+    MIGRATOR.run(pool).await?;
+
+    if !had_roles {
+        sqlx::query("UPDATE users SET role='admin' WHERE username=?;")
+            .bind(vars.admin_account_username.as_str())
+            .execute(pool)
+            .await?;
+    }
+
+    if let Some(admin_password) = &vars.admin_account_password {
+        let result = sqlx::query(
+            "INSERT OR IGNORE INTO users (username, password, role) VALUES (?, ?, 'admin');",
+        )
+        .bind(vars.admin_account_username.as_str())
+        .bind(password::hash_password(admin_password).await?)
+        .execute(pool)
+        .await?;
+
+        if result.rows_affected() > 0 {
+            info!("Created admin account {}", vars.admin_account_username);
+        }
+    }
+
+    Ok(())
+}
+
+/// A migrated auth DB in memory for tests. Every connection to ``:memory:`` gets its own DB, so the pool keeps
+/// exactly one connection open for its whole life.
+#[cfg(test)]
+pub(crate) async fn memory_pool() -> SqlitePool {
+    let pool = PoolOptions::new()
+        .max_connections(1)
+        .idle_timeout(None)
+        .max_lifetime(None)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    run_migrations(&pool, &BackendVars::for_tests())
+        .await
+        .unwrap();
+
+    pool
+}
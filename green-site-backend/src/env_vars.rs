@@ -17,8 +17,117 @@ use green_site_backend_macros::env_vars;
 #[env_var("DATA_HISTORIAN_PASS", String)]
 #[env_var("DATA_HISTORIAN_DB_NAME", String)]
 #[env_var("DATA_HISTORIAN_DB_TABLE", String)]
-#[env_var("WEB_SERVER_PORT", u16)]
 #[env_var("ADMIN_ACCOUNT_USERNAME", String)]
-#[env_var("ADMIN_TOKEN", String)]
+#[env_var("SSL_CERTIFICATE_PEM_PATH", String)]
+#[env_var("SSL_PRIVATE_KEY_PEM_PATH", String)]
 #[env_var("ROOT_CERTIFICATE_PATH", String)]
+#[optional_env_var("HTTP_REDIRECT_PORT", u16)]
+#[optional_env_var("HTTP_REDIRECT_HOST", String)]
+#[optional_env_var("FTPS_TLS_NAME", String)]
+#[optional_env_var("FILE_ENDPOINTS", String)]
+#[optional_env_var("MAX_UPLOAD_BYTES", u64)]
+#[optional_env_var("EMAIL_TLS_NAME", String)]
+#[optional_env_var("DATA_HISTORIAN_TLS_NAME", String)]
+#[optional_env_var("TLS_ACCEPT_INVALID_HOSTNAMES", bool)]
+#[optional_env_var("UPSTREAM_TLS_MIN_VERSION", String)]
+#[optional_env_var("WEB_SERVER_PORT", u16)]
+#[optional_env_var("WEB_SERVER_BIND", String)]
+#[optional_env_var("ADMIN_ACCOUNT_PASSWORD", String)]
+#[optional_env_var("LOGIN_LOCKOUT_PERSIST", bool)]
+#[optional_env_var("PASSWORD_MIN_LENGTH", usize)]
+#[optional_env_var("PASSWORD_MIN_CHARACTER_CLASSES", u8)]
+#[optional_env_var("SECURITY_LOG_SINK", String)]
+#[optional_env_var("CREDENTIAL_BACKEND", String)]
+#[optional_env_var("LDAP_URL", String)]
+#[optional_env_var("LDAP_USER_DN_TEMPLATE", String)]
+#[optional_env_var("TOKEN_MODE", String)]
+#[optional_env_var("AUTH_COOKIES", bool)]
+#[optional_env_var("REQUIRE_CLIENT_CERTS", bool)]
+#[optional_env_var("JWT_ALGORITHM", String)]
+#[optional_env_var("JWT_SIGNING_KEY", String)]
+#[optional_env_var("JWT_VERIFYING_KEY", String)]
 pub(crate) struct BackendVars;
+
+impl BackendVars {
+    /// The name the FTPS server's certificate must be valid for.
+    pub fn ftps_server_name(&self) -> &str {
+        self.ftps_tls_name
+            .as_deref()
+            .unwrap_or(&self.ftps_server_ip)
+    }
+
+    /// The name the mail server's certificate must be valid for, for both SMTP and IMAP.
+    pub fn email_server_name(&self) -> &str {
+        self.email_tls_name
+            .as_deref()
+            .unwrap_or(&self.email_server_ip)
+    }
+
+    /// The name the Data Historian's certificate must be valid for. sqlx verifies the host it connects to, so this is
+    /// also the host MySQL connections are made to.
+    pub fn data_historian_server_name(&self) -> &str {
+        self.data_historian_tls_name
+            .as_deref()
+            .unwrap_or(&self.data_historian_ip)
+    }
+
+    /// Whether upstream TLS connections skip hostname verification, trusting any certificate from the root CA.
+    pub fn accept_invalid_hostnames(&self) -> bool {
+        self.tls_accept_invalid_hostnames.unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+impl BackendVars {
+    /// Settings for tests with every optional variable unset, built directly so tests don't race on the process
+    /// environment.
+    pub fn for_tests() -> Self {
+        Self {
+            sqlite_file_name: "sqlite::memory:".to_string(),
+            ftps_server_ip: "127.0.0.1".to_string(),
+            ftps_server_port: 990,
+            ftps_user: "green".to_string(),
+            ftps_pass: "site".to_string(),
+            email_server_ip: "127.0.0.1".to_string(),
+            smtp_server_port: 465,
+            imap_server_port: 993,
+            email_user: "green".to_string(),
+            email_pass: "site".to_string(),
+            data_historian_ip: "127.0.0.1".to_string(),
+            data_historian_port: 3306,
+            data_historian_user: "green".to_string(),
+            data_historian_pass: "site".to_string(),
+            data_historian_db_name: "green".to_string(),
+            data_historian_db_table: "solar".to_string(),
+            admin_account_username: "admin".to_string(),
+            ssl_certificate_pem_path: "cert.pem".to_string(),
+            ssl_private_key_pem_path: "key.pem".to_string(),
+            root_certificate_path: "root.pem".to_string(),
+            http_redirect_port: None,
+            http_redirect_host: None,
+            ftps_tls_name: None,
+            file_endpoints: None,
+            max_upload_bytes: None,
+            email_tls_name: None,
+            data_historian_tls_name: None,
+            tls_accept_invalid_hostnames: None,
+            upstream_tls_min_version: None,
+            web_server_port: None,
+            web_server_bind: None,
+            admin_account_password: None,
+            login_lockout_persist: None,
+            password_min_length: None,
+            password_min_character_classes: None,
+            security_log_sink: None,
+            credential_backend: None,
+            ldap_url: None,
+            ldap_user_dn_template: None,
+            token_mode: None,
+            auth_cookies: None,
+            require_client_certs: None,
+            jwt_algorithm: None,
+            jwt_signing_key: None,
+            jwt_verifying_key: None,
+        }
+    }
+}
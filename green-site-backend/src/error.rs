@@ -1,8 +1,8 @@
-use actix_web::HttpResponse;
+use actix_web::{rt::task::JoinError, HttpResponse};
+use ldap3::LdapError;
 use lettre::transport::smtp::Error as SmtpError;
 use serde::Serialize;
 use std::{error::Error, fmt::Display, io};
-use suppaftp::async_native_tls;
 use CertConfigError::*;
 
 const INTERNAL_ERROR: &str = "Internal server error encountered. Please try again later.";
@@ -13,14 +13,25 @@ pub(crate) const MISSING_APP_DATA: &str = "Missing app data. This should never h
 pub(crate) enum CertConfigError {
     ReadPemIoError(String, io::Error),
 
-    BadRootCertificate(Option<SmtpError>, Option<async_native_tls::Error>),
+    BadRootCertificate(Option<SmtpError>, Option<webpki::Error>),
+
+    BadServerCertificate(String),
+
+    TlsConfigError(rustls::Error),
+
+    LdapTlsConfigError(ldap_rustls::Error),
 }
 
 impl Display for CertConfigError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ReadPemIoError(file, _) => write!(f, "Error while reading PEM file: {file}"),
-            BadRootCertificate(err, _) => write!(f, "Bad root certificate provided: {err:?}"),
+            BadRootCertificate(smtp_err, tls_err) => {
+                write!(f, "Bad root certificate provided: {smtp_err:?} {tls_err:?}")
+            }
+            BadServerCertificate(msg) => write!(f, "Bad server certificate provided: {msg}"),
+            TlsConfigError(err) => write!(f, "Couldn't configure TLS: {err}"),
+            LdapTlsConfigError(err) => write!(f, "Couldn't configure TLS for LDAP: {err}"),
         }
     }
 }
@@ -31,21 +42,118 @@ impl From<SmtpError> for CertConfigError {
     }
 }
 
-impl From<async_native_tls::Error> for CertConfigError {
-    fn from(value: async_native_tls::Error) -> Self {
+impl From<webpki::Error> for CertConfigError {
+    fn from(value: webpki::Error) -> Self {
         BadRootCertificate(None, Some(value))
     }
 }
 
+impl From<rustls::Error> for CertConfigError {
+    fn from(value: rustls::Error) -> Self {
+        TlsConfigError(value)
+    }
+}
+
+impl From<ldap_rustls::Error> for CertConfigError {
+    fn from(value: ldap_rustls::Error) -> Self {
+        LdapTlsConfigError(value)
+    }
+}
+
 impl Error for CertConfigError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             ReadPemIoError(_, err) => Some(err),
+            TlsConfigError(err) => Some(err),
+            LdapTlsConfigError(err) => Some(err),
             _ => None,
         }
     }
 }
 
+/// An error from a credential backend while checking a password. Bad credentials aren't an error.
+#[derive(Debug)]
+pub(crate) enum CredentialError {
+    Sqlx(sqlx::Error),
+    Ldap(LdapError),
+    PasswordHash(PasswordError),
+}
+
+impl Display for CredentialError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CredentialError::Sqlx(err) => write!(f, "sqlx error: {err}"),
+            CredentialError::Ldap(err) => write!(f, "LDAP error: {err}"),
+            CredentialError::PasswordHash(err) => write!(f, "Password hashing error: {err}"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for CredentialError {
+    fn from(value: sqlx::Error) -> Self {
+        CredentialError::Sqlx(value)
+    }
+}
+
+impl From<LdapError> for CredentialError {
+    fn from(value: LdapError) -> Self {
+        CredentialError::Ldap(value)
+    }
+}
+
+impl From<PasswordError> for CredentialError {
+    fn from(value: PasswordError) -> Self {
+        CredentialError::PasswordHash(value)
+    }
+}
+
+impl Error for CredentialError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CredentialError::Sqlx(err) => Some(err),
+            CredentialError::Ldap(err) => Some(err),
+            CredentialError::PasswordHash(err) => Some(err),
+        }
+    }
+}
+
+/// An error while hashing or verifying a password on the blocking thread pool.
+#[derive(Debug)]
+pub(crate) enum PasswordError {
+    Hash(argon2::password_hash::Error),
+    Join(JoinError),
+}
+
+impl Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordError::Hash(err) => write!(f, "Argon2 error: {err}"),
+            PasswordError::Join(err) => write!(f, "Hashing task failed: {err}"),
+        }
+    }
+}
+
+impl From<argon2::password_hash::Error> for PasswordError {
+    fn from(value: argon2::password_hash::Error) -> Self {
+        PasswordError::Hash(value)
+    }
+}
+
+impl From<JoinError> for PasswordError {
+    fn from(value: JoinError) -> Self {
+        PasswordError::Join(value)
+    }
+}
+
+impl Error for PasswordError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PasswordError::Hash(_) => None,
+            PasswordError::Join(err) => Some(err),
+        }
+    }
+}
+
 #[derive(Serialize)]
 pub(crate) struct ErrorResponse {
     pub error: String,
@@ -0,0 +1,243 @@
+use std::{
+    fs, io,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+
+use crate::{env_vars::BackendVars, role::Role, token::SessionToken};
+
+/// How long a JWT stays valid after it was issued. Kept shorter than sessions since JWTs can't be revoked.
+const JWT_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// HS256 secrets shorter than the hash output make brute forcing the key easier.
+const MIN_SECRET_LEN: usize = 32;
+
+/// The claims of every JWT issued by ``login``.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Claims {
+    pub sub: String,
+    pub role: Role,
+    pub iat: u64,
+    pub exp: u64,
+}
+
+/// The keys JWTs are signed and verified with. Every replica must be given the same keys.
+#[derive(Clone)]
+pub(crate) struct JwtKeys {
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+fn bad_config(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn read_pem(var: &str, path: &str) -> io::Result<Vec<u8>> {
+    fs::read(path).map_err(|e| bad_config(format!("Couldn't read {var} {path}: {e}")))
+}
+
+impl JwtKeys {
+    /// Loads the keys set by ``JWT_ALGORITHM``, which is ``HS256`` (default) or ``EdDSA``. For HS256,
+    /// ``JWT_SIGNING_KEY`` is the shared secret. For EdDSA, ``JWT_SIGNING_KEY`` and ``JWT_VERIFYING_KEY`` are paths of
+    /// the Ed25519 private and public key PEMs.
+    pub fn from_vars(vars: &BackendVars) -> io::Result<Self> {
+        let signing_key = vars
+            .jwt_signing_key
+            .as_deref()
+            .ok_or_else(|| bad_config("TOKEN_MODE=jwt requires JWT_SIGNING_KEY".to_string()))?;
+
+        match vars.jwt_algorithm.as_deref() {
+            None | Some("HS256") => {
+                if signing_key.len() < MIN_SECRET_LEN {
+                    return Err(bad_config(format!(
+                        "JWT_SIGNING_KEY must be at least {MIN_SECRET_LEN} bytes for HS256"
+                    )));
+                }
+
+                Ok(Self {
+                    algorithm: Algorithm::HS256,
+                    encoding: EncodingKey::from_secret(signing_key.as_bytes()),
+                    decoding: DecodingKey::from_secret(signing_key.as_bytes()),
+                })
+            }
+            Some("EdDSA") => {
+                let verifying_key = vars.jwt_verifying_key.as_deref().ok_or_else(|| {
+                    bad_config("JWT_ALGORITHM=EdDSA requires JWT_VERIFYING_KEY".to_string())
+                })?;
+                let encoding = EncodingKey::from_ed_pem(&read_pem("JWT_SIGNING_KEY", signing_key)?)
+                    .map_err(|e| bad_config(format!("Bad JWT_SIGNING_KEY: {e}")))?;
+                let decoding =
+                    DecodingKey::from_ed_pem(&read_pem("JWT_VERIFYING_KEY", verifying_key)?)
+                        .map_err(|e| bad_config(format!("Bad JWT_VERIFYING_KEY: {e}")))?;
+
+                Ok(Self {
+                    algorithm: Algorithm::EdDSA,
+                    encoding,
+                    decoding,
+                })
+            }
+            Some(algorithm) => Err(bad_config(format!(
+                "Bad JWT_ALGORITHM {algorithm:?}. Expected HS256 or EdDSA"
+            ))),
+        }
+    }
+
+    /// Signs a JWT for the user that expires after ``JWT_LIFETIME``.
+    pub fn issue(&self, username: &str, role: Role) -> jsonwebtoken::errors::Result<SessionToken> {
+        let iat = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let claims = Claims {
+            sub: username.to_string(),
+            role,
+            iat,
+            exp: iat + JWT_LIFETIME.as_secs(),
+        };
+
+        jsonwebtoken::encode(&Header::new(self.algorithm), &claims, &self.encoding)
+            .map(SessionToken::new)
+    }
+
+    /// Verifies the signature and expiry of the JWT without touching the DB. Only the configured algorithm is
+    /// accepted, so a token can't pick its own.
+    pub fn verify(&self, token: &str) -> jsonwebtoken::errors::Result<Claims> {
+        let mut validation = Validation::new(self.algorithm);
+
+        validation.leeway = 0;
+        validation.set_required_spec_claims(&["exp", "iat", "sub"]);
+
+        jsonwebtoken::decode(token, &self.decoding, &validation).map(|data| data.claims)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::errors::ErrorKind;
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SECRET: &str = "an HS256 secret of at least 32 bytes";
+    /// ``{"alg":"none","typ":"JWT"}`` in base64url.
+    const UNSIGNED_HEADER: &str = "eyJhbGciOiJub25lIiwidHlwIjoiSldUIn0";
+
+    fn hs256_keys(secret: &str) -> io::Result<JwtKeys> {
+        JwtKeys::from_vars(&BackendVars {
+            jwt_signing_key: Some(secret.to_string()),
+            ..BackendVars::for_tests()
+        })
+    }
+
+    /// EdDSA keys from a new Ed25519 key pair, with the public key PEM.
+    fn eddsa_keys(dir: &TempDir) -> (JwtKeys, String) {
+        let key_pair = rcgen::KeyPair::generate(&rcgen::PKCS_ED25519).unwrap();
+        let (private_path, public_path) = (dir.path().join("jwt.pem"), dir.path().join("jwt.pub"));
+
+        fs::write(&private_path, key_pair.serialize_pem()).unwrap();
+        fs::write(&public_path, key_pair.public_key_pem()).unwrap();
+
+        let keys = JwtKeys::from_vars(&BackendVars {
+            jwt_algorithm: Some("EdDSA".to_string()),
+            jwt_signing_key: Some(private_path.to_str().unwrap().to_string()),
+            jwt_verifying_key: Some(public_path.to_str().unwrap().to_string()),
+            ..BackendVars::for_tests()
+        })
+        .unwrap();
+
+        (keys, key_pair.public_key_pem())
+    }
+
+    fn error_kind(result: jsonwebtoken::errors::Result<Claims>) -> ErrorKind {
+        result.unwrap_err().into_kind()
+    }
+
+    #[test]
+    fn verifies_issued_tokens() {
+        let keys = hs256_keys(SECRET).unwrap();
+        let token = keys.issue("operator", Role::Operator).unwrap();
+        let claims = keys.verify(token.as_str()).unwrap();
+
+        assert_eq!(claims.sub, "operator");
+        assert_eq!(claims.role, Role::Operator);
+        assert_eq!(claims.exp - claims.iat, JWT_LIFETIME.as_secs());
+
+        let dir = TempDir::new().unwrap();
+        let (keys, _) = eddsa_keys(&dir);
+        let token = keys.issue("admin", Role::Admin).unwrap();
+
+        assert_eq!(keys.verify(token.as_str()).unwrap().sub, "admin");
+    }
+
+    #[test]
+    fn rejects_short_hs256_secrets() {
+        assert!(hs256_keys(&SECRET[..MIN_SECRET_LEN - 1]).is_err());
+        assert!(JwtKeys::from_vars(&BackendVars::for_tests()).is_err());
+    }
+
+    #[test]
+    fn rejects_expired_tokens() {
+        let keys = hs256_keys(SECRET).unwrap();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let claims = Claims {
+            sub: "operator".to_string(),
+            role: Role::Operator,
+            iat: now - JWT_LIFETIME.as_secs() - 1,
+            exp: now - 1,
+        };
+        let token =
+            jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &keys.encoding).unwrap();
+
+        assert_eq!(error_kind(keys.verify(&token)), ErrorKind::ExpiredSignature);
+    }
+
+    #[test]
+    fn rejects_tokens_signed_with_other_keys() {
+        let token = hs256_keys(SECRET)
+            .unwrap()
+            .issue("admin", Role::Admin)
+            .unwrap();
+        let other_keys = hs256_keys("a different HS256 secret of 32 bytes").unwrap();
+
+        assert_eq!(
+            error_kind(other_keys.verify(token.as_str())),
+            ErrorKind::InvalidSignature
+        );
+
+        let dir = TempDir::new().unwrap();
+        let (keys, _) = eddsa_keys(&dir);
+        let (other_keys, _) = eddsa_keys(&TempDir::new().unwrap());
+        let token = other_keys.issue("admin", Role::Admin).unwrap();
+
+        assert_eq!(
+            error_kind(keys.verify(token.as_str())),
+            ErrorKind::InvalidSignature
+        );
+    }
+
+    #[test]
+    fn rejects_tokens_choosing_another_algorithm() {
+        let dir = TempDir::new().unwrap();
+        let (keys, public_key_pem) = eddsa_keys(&dir);
+        // The classic confusion attack: an HS256 token using the public key as its secret.
+        let forged_keys = hs256_keys(&public_key_pem).unwrap();
+        let forged = forged_keys.issue("admin", Role::Admin).unwrap();
+
+        assert_eq!(
+            error_kind(keys.verify(forged.as_str())),
+            ErrorKind::InvalidAlgorithm
+        );
+
+        // Unsigned tokens are rejected whatever algorithm is configured.
+        let payload = forged.as_str().split('.').nth(1).unwrap();
+        let unsigned = format!("{UNSIGNED_HEADER}.{payload}.");
+
+        assert!(keys.verify(&unsigned).is_err());
+        assert!(forged_keys.verify(&unsigned).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use actix_web::{http::header::HeaderName, HttpRequest, HttpResponse};
+
+use crate::{env_vars::BackendVars, error::ErrorResponse};
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// An address the web server listens on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Listener {
+    Tcp { addr: SocketAddr, tls: bool },
+    Unix(PathBuf),
+}
+
+impl Listener {
+    /// Parses ``https://<IP>:<PORT>``, ``http://<IP>:<PORT>`` or ``unix:<PATH>``. IPv6 addresses go in brackets.
+    fn parse(listener: &str) -> io::Result<Self> {
+        let bad_listener = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Bad WEB_SERVER_BIND listener {listener:?}. Expected https://<IP>:<PORT>, http://<IP>:<PORT> or unix:<PATH>"
+                ),
+            )
+        };
+        let tcp = |addr: &str, tls| {
+            addr.parse()
+                .map(|addr| Listener::Tcp { addr, tls })
+                .map_err(|_| bad_listener())
+        };
+
+        if let Some(addr) = listener.strip_prefix("https://") {
+            tcp(addr, true)
+        } else if let Some(addr) = listener.strip_prefix("http://") {
+            tcp(addr, false)
+        } else {
+            match listener.strip_prefix("unix:") {
+                Some(path) if !path.is_empty() => Ok(Listener::Unix(path.into())),
+                _ => Err(bad_listener()),
+            }
+        }
+    }
+}
+
+/// The listeners in the comma separated ``WEB_SERVER_BIND``. If it's unset, the web server only listens with HTTPS on
+/// ``127.0.0.1:<WEB_SERVER_PORT>``.
+pub(crate) fn listeners(vars: &BackendVars) -> io::Result<Vec<Listener>> {
+    match (&vars.web_server_bind, vars.web_server_port) {
+        (Some(bind), _) => {
+            let listeners = bind
+                .split(',')
+                .map(str::trim)
+                .filter(|listener| !listener.is_empty())
+                .map(Listener::parse)
+                .collect::<io::Result<Vec<_>>>()?;
+
+            if listeners.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "WEB_SERVER_BIND has no listeners",
+                ));
+            }
+
+            Ok(listeners)
+        }
+        (None, Some(port)) => Ok(vec![Listener::Tcp {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            tls: true,
+        }]),
+        (None, None) => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Either WEB_SERVER_BIND or WEB_SERVER_PORT must be set",
+        )),
+    }
+}
+
+/// The address of the first HTTPS listener. The HTTP redirect listener listens on its IP and redirects to its port.
+pub(crate) fn first_https_addr(listeners: &[Listener]) -> Option<SocketAddr> {
+    listeners.iter().find_map(|listener| match listener {
+        Listener::Tcp { addr, tls: true } => Some(*addr),
+        _ => None,
+    })
+}
+
+/// The address rate limits and login throttling key the client by. It's the peer IP on TCP listeners. Unix socket
+/// listeners have no peer address and can only be reached by local processes, so they're expected to be behind a
+/// reverse proxy. There, the last ``X-Forwarded-For`` entry is used since it's the one the proxy added. ``None`` if
+/// there's no usable address, so such clients don't all share one key.
+pub(crate) fn client_addr(req: &HttpRequest) -> Option<String> {
+    if let Some(peer_addr) = req.connection_info().peer_addr() {
+        return Some(peer_addr.to_string());
+    }
+
+    let forwarded_for = req
+        .headers()
+        .get_all(X_FORWARDED_FOR)
+        .last()?
+        .to_str()
+        .ok()?;
+    let client_ip: IpAddr = forwarded_for.rsplit(',').next()?.trim().parse().ok()?;
+
+    Some(client_ip.to_string())
+}
+
+/// The response to a request on a rate-limited endpoint without a client address to key it by.
+pub(crate) fn unknown_client() -> HttpResponse {
+    HttpResponse::BadRequest().json(ErrorResponse {
+        error: "Couldn't determine the client address.".to_string(),
+    })
+}
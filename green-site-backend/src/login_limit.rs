@@ -0,0 +1,405 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use sqlx::SqlitePool;
+
+use crate::env_vars::BackendVars;
+
+/// How far back login attempts are counted for the sliding window limits.
+const ATTEMPT_WINDOW: Duration = Duration::from_secs(60);
+const MAX_ATTEMPTS_PER_IP: usize = 10;
+const MAX_ATTEMPTS_PER_USERNAME: usize = 5;
+/// How many failures in a row lock an account, and for how long.
+const LOCKOUT_THRESHOLD: u32 = 5;
+const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+/// How many IPs or usernames are tracked before stale windows are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Consecutive failed logins of a username. ``locked_until`` is a Unix timestamp in seconds.
+#[derive(Debug, Default, Clone, Copy)]
+struct Failures {
+    count: u32,
+    locked_until: i64,
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    ip_attempts: HashMap<String, VecDeque<Instant>>,
+    username_attempts: HashMap<String, VecDeque<Instant>>,
+    failures: HashMap<String, Failures>,
+}
+
+/// Throttles ``/api/login`` with per-IP and per-username sliding windows and locks accounts after too many
+/// consecutive failures. The counters live in memory and the lockouts are also kept in the ``login_lockouts``
+/// table of the SQLite DB when ``LOGIN_LOCKOUT_PERSIST`` is true.
+#[derive(Debug, Clone)]
+pub(crate) struct LoginLimiter {
+    state: Arc<Mutex<LimiterState>>,
+    pool: Option<SqlitePool>,
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Records an attempt in the window unless it's full, in which case the time until the oldest attempt expires is returned.
+fn push_attempt(
+    attempts: &mut HashMap<String, VecDeque<Instant>>,
+    key: &str,
+    max: usize,
+    now: Instant,
+) -> Result<(), Duration> {
+    let window = attempts.entry(key.to_string()).or_default();
+
+    while window
+        .front()
+        .filter(|&&t| now.duration_since(t) >= ATTEMPT_WINDOW)
+        .is_some()
+    {
+        window.pop_front();
+    }
+
+    match window.front() {
+        Some(&oldest) if window.len() >= max => Err(ATTEMPT_WINDOW - now.duration_since(oldest)),
+        _ => {
+            window.push_back(now);
+
+            Ok(())
+        }
+    }
+}
+
+impl LoginLimiter {
+    /// Creates the limiter, loading any persisted lockouts.
+    pub async fn new(pool: &SqlitePool, vars: &BackendVars) -> sqlx::Result<Self> {
+        let mut state = LimiterState::default();
+        let pool = match vars.login_lockout_persist {
+            Some(true) => Some(pool.clone()),
+            _ => None,
+        };
+
+        if let Some(pool) = &pool {
+            let rows: Vec<(String, u32, i64)> =
+                sqlx::query_as("SELECT username, failures, locked_until FROM login_lockouts;")
+                    .fetch_all(pool)
+                    .await?;
+
+            for (username, count, locked_until) in rows {
+                state.failures.insert(
+                    username,
+                    Failures {
+                        count,
+                        locked_until,
+                    },
+                );
+            }
+        }
+
+        Ok(Self {
+            state: Arc::new(Mutex::new(state)),
+            pool,
+        })
+    }
+
+    /// Counts a login attempt from the IP for the username. If the attempt isn't allowed, returns how long
+    /// the client should wait before retrying.
+    pub fn check(&self, ip: &str, username: &str) -> Result<(), Duration> {
+        self.check_at(ip, username, Instant::now(), unix_now())
+    }
+
+    /// [`Self::check`] at the given time, where ``unix_time`` is in seconds.
+    fn check_at(
+        &self,
+        ip: &str,
+        username: &str,
+        now: Instant,
+        unix_time: i64,
+    ) -> Result<(), Duration> {
+        let mut guard = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut *guard;
+        let locked_for = state
+            .failures
+            .get(username)
+            .map(|f| f.locked_until - unix_time)
+            .filter(|&secs| secs > 0);
+
+        if let Some(secs) = locked_for {
+            return Err(Duration::from_secs(secs as u64));
+        }
+
+        for attempts in [&mut state.ip_attempts, &mut state.username_attempts] {
+            if attempts.len() > PRUNE_THRESHOLD {
+                attempts.retain(|_, window| {
+                    window
+                        .back()
+                        .filter(|&&t| now.duration_since(t) < ATTEMPT_WINDOW)
+                        .is_some()
+                });
+            }
+        }
+
+        push_attempt(&mut state.ip_attempts, ip, MAX_ATTEMPTS_PER_IP, now)?;
+        push_attempt(
+            &mut state.username_attempts,
+            username,
+            MAX_ATTEMPTS_PER_USERNAME,
+            now,
+        )
+    }
+
+    /// Counts a failed login of the username, locking the account once the threshold is reached.
+    pub async fn record_failure(&self, username: &str) {
+        self.record_failure_at(username, unix_now()).await
+    }
+
+    /// [`Self::record_failure`] at the Unix time in seconds.
+    async fn record_failure_at(&self, username: &str, unix_time: i64) {
+        let failures = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            let failures = state.failures.entry(username.to_string()).or_default();
+
+            failures.count += 1;
+
+            if failures.count >= LOCKOUT_THRESHOLD {
+                warn!(
+                    "Locking {username} for {}s after {} consecutive failed logins",
+                    LOCKOUT_DURATION.as_secs(),
+                    failures.count,
+                );
+
+                failures.count = 0;
+                failures.locked_until = unix_time + LOCKOUT_DURATION.as_secs() as i64;
+            }
+
+            *failures
+        };
+
+        if let Err(err) = self.persist(username, Some(failures)).await {
+            error!("Encountered sqlx error while persisting login lockout: {err}");
+        }
+    }
+
+    /// Resets the consecutive failures of the username after a successful login.
+    pub async fn record_success(&self, username: &str) {
+        let had_failures = self
+            .state
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .failures
+            .remove(username)
+            .is_some();
+
+        if had_failures {
+            if let Err(err) = self.persist(username, None).await {
+                error!("Encountered sqlx error while persisting login lockout: {err}");
+            }
+        }
+    }
+
+    async fn persist(&self, username: &str, failures: Option<Failures>) -> sqlx::Result<()> {
+        let pool = match &self.pool {
+            Some(pool) => pool,
+            None => return Ok(()),
+        };
+
+        match failures {
+            Some(Failures {
+                count,
+                locked_until,
+            }) => {
+                sqlx::query(
+                    "INSERT OR REPLACE INTO login_lockouts (username, failures, locked_until) \
+                     VALUES (?, ?, ?);",
+                )
+                .bind(username)
+                .bind(count)
+                .bind(locked_until)
+                .execute(pool)
+                .await?;
+            }
+            None => {
+                sqlx::query("DELETE FROM login_lockouts WHERE username=?;")
+                    .bind(username)
+                    .execute(pool)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db;
+
+    const START: i64 = 1_600_000_000;
+
+    async fn limiter(persist: bool) -> (LoginLimiter, SqlitePool) {
+        let pool = db::memory_pool().await;
+        let vars = BackendVars {
+            login_lockout_persist: Some(persist),
+            ..BackendVars::for_tests()
+        };
+
+        (LoginLimiter::new(&pool, &vars).await.unwrap(), pool)
+    }
+
+    #[actix_web::test]
+    async fn limits_attempts_per_ip() {
+        let (limiter, _pool) = limiter(false).await;
+        let now = Instant::now();
+
+        for i in 0..MAX_ATTEMPTS_PER_IP {
+            let username = format!("user{i}");
+
+            assert_eq!(limiter.check_at("10.0.0.1", &username, now, START), Ok(()));
+        }
+
+        let later = now + Duration::from_secs(20);
+
+        assert_eq!(
+            limiter.check_at("10.0.0.1", "other", later, START + 20),
+            Err(Duration::from_secs(40))
+        );
+        // Other IPs have their own window.
+        assert_eq!(
+            limiter.check_at("10.0.0.2", "other", later, START + 20),
+            Ok(())
+        );
+
+        let expired = now + ATTEMPT_WINDOW;
+
+        assert_eq!(
+            limiter.check_at("10.0.0.1", "other", expired, START + 60),
+            Ok(())
+        );
+    }
+
+    #[actix_web::test]
+    async fn limits_attempts_per_username() {
+        let (limiter, _pool) = limiter(false).await;
+        let now = Instant::now();
+
+        for i in 0..MAX_ATTEMPTS_PER_USERNAME {
+            let ip = format!("10.0.0.{i}");
+            let at = now + Duration::from_secs(i as u64);
+
+            assert_eq!(limiter.check_at(&ip, "admin", at, START), Ok(()));
+        }
+
+        let later = now + Duration::from_secs(30);
+
+        assert_eq!(
+            limiter.check_at("10.0.1.1", "admin", later, START + 30),
+            Err(Duration::from_secs(30))
+        );
+
+        // The window slides, so only the oldest attempt has expired after a minute.
+        let slid = now + ATTEMPT_WINDOW;
+
+        assert_eq!(
+            limiter.check_at("10.0.1.1", "admin", slid, START + 60),
+            Ok(())
+        );
+        assert_eq!(
+            limiter.check_at("10.0.1.2", "admin", slid, START + 60),
+            Err(Duration::from_secs(1))
+        );
+    }
+
+    #[actix_web::test]
+    async fn locks_after_consecutive_failures() {
+        let (limiter, _pool) = limiter(false).await;
+        let now = Instant::now();
+
+        for _ in 0..LOCKOUT_THRESHOLD - 1 {
+            limiter.record_failure_at("admin", START).await;
+        }
+
+        assert_eq!(limiter.check_at("10.0.0.1", "admin", now, START), Ok(()));
+
+        limiter.record_failure_at("admin", START).await;
+
+        assert_eq!(
+            limiter.check_at("10.0.0.1", "admin", now, START + 100),
+            Err(LOCKOUT_DURATION - Duration::from_secs(100))
+        );
+
+        let unlocked = START + LOCKOUT_DURATION.as_secs() as i64;
+
+        assert_eq!(
+            limiter.check_at("10.0.0.1", "admin", now + LOCKOUT_DURATION, unlocked),
+            Ok(())
+        );
+    }
+
+    #[actix_web::test]
+    async fn success_resets_consecutive_failures() {
+        let (limiter, _pool) = limiter(false).await;
+        let now = Instant::now();
+
+        for _ in 0..LOCKOUT_THRESHOLD - 1 {
+            limiter.record_failure_at("admin", START).await;
+        }
+
+        limiter.record_success("admin").await;
+
+        for _ in 0..LOCKOUT_THRESHOLD - 1 {
+            limiter.record_failure_at("admin", START).await;
+        }
+
+        assert_eq!(limiter.check_at("10.0.0.1", "admin", now, START), Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn persists_lockouts_when_enabled() {
+        let (limiter, pool) = limiter(true).await;
+        let now = Instant::now();
+
+        for _ in 0..LOCKOUT_THRESHOLD {
+            limiter.record_failure_at("admin", START).await;
+        }
+
+        let vars = BackendVars {
+            login_lockout_persist: Some(true),
+            ..BackendVars::for_tests()
+        };
+        let restarted = LoginLimiter::new(&pool, &vars).await.unwrap();
+
+        assert_eq!(
+            restarted.check_at("10.0.0.1", "admin", now, START),
+            Err(LOCKOUT_DURATION)
+        );
+
+        restarted.record_success("admin").await;
+
+        let restarted = LoginLimiter::new(&pool, &vars).await.unwrap();
+
+        assert_eq!(restarted.check_at("10.0.0.1", "admin", now, START), Ok(()));
+    }
+
+    #[actix_web::test]
+    async fn keeps_lockouts_in_memory_by_default() {
+        let (limiter, pool) = limiter(false).await;
+
+        for _ in 0..LOCKOUT_THRESHOLD {
+            limiter.record_failure_at("admin", START).await;
+        }
+
+        let (rows,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM login_lockouts;")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(rows, 0);
+    }
+}
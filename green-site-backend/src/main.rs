@@ -1,95 +1,162 @@
-use std::{error::Error, fs::File, io::Read, time::Duration};
+use std::{env, error::Error, sync::Arc, time::Duration};
 
 use actix_web::{
-    middleware::{self, Logger, TrailingSlash},
-    web, App, HttpServer,
+    middleware::{self, Condition, Logger, TrailingSlash},
+    web, App, HttpRequest, HttpServer,
 };
 use env_logger::Builder;
 use env_vars::BackendVars;
-use error::CertConfigError;
-use lettre::transport::smtp::client::Certificate as SmtpCertificate;
-use log::LevelFilter;
-use native_tls::{Protocol, TlsConnector};
+use futures::future;
+use listener::Listener;
+use log::{warn, LevelFilter};
 use sqlx::{mysql::MySqlConnectOptions, pool::PoolOptions, MySqlPool};
-use suppaftp::async_native_tls::Certificate as FtpCertificate;
 
 mod api;
+mod client_cert;
+mod credentials;
+mod csrf;
+mod db;
 mod env_vars;
 mod error;
+mod jwt;
+mod listener;
+mod login_limit;
+mod password;
+mod rate_limit;
+mod role;
+mod security_log;
+mod session;
+mod tls;
+mod tls_policy;
 mod token;
+mod totp;
+mod users;
 
-fn get_trusted_roots(
-    vars: &BackendVars,
-) -> Result<(FtpCertificate, SmtpCertificate), CertConfigError> {
-    use CertConfigError::*;
-
-    let root_cert_path = vars.root_certificate_path.as_str();
-    let mut root_cert_file = match File::open(root_cert_path) {
-        Ok(ok) => ok,
-        Err(err) => return Err(ReadPemIoError(root_cert_path.to_string(), err)),
-    };
-    let mut root_cert_bytes = Vec::new();
-    root_cert_file
-        .read_to_end(&mut root_cert_bytes)
-        .map_err(|e| ReadPemIoError(root_cert_path.to_string(), e))?;
-
-    Ok((
-        FtpCertificate::from_pem(&root_cert_bytes)?,
-        SmtpCertificate::from_pem(&root_cert_bytes)?,
-    ))
-}
-
-fn create_pool(vars: &BackendVars) -> MySqlPool {
+fn create_pool(vars: &BackendVars, tls_policy: tls_policy::TlsPolicy) -> MySqlPool {
     let conn_options = MySqlConnectOptions::new()
-        .host(&vars.data_historian_ip)
+        .host(vars.data_historian_server_name())
         .port(vars.data_historian_port)
         .username(&vars.data_historian_user)
         .password(&vars.data_historian_pass)
         .database(&vars.data_historian_db_name);
+    let conn_options = tls_policy.mysql_options(conn_options, vars);
 
     PoolOptions::new()
         .max_connections(50)
         .min_connections(2)
         .acquire_timeout(Duration::from_secs(3))
         .max_lifetime(Some(Duration::from_secs(3600)))
+        .after_connect(move |conn, _| {
+            Box::pin(async move { tls_policy.check_mysql_version(conn).await })
+        })
         .connect_lazy_with(conn_options)
 }
 
 #[actix_web::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let backend_vars = BackendVars::new()?;
-    let port = backend_vars.web_server_port;
-    let (native_cert, smtp_cert) = get_trusted_roots(&backend_vars)?;
-    let mysql_pool = create_pool(&backend_vars);
-    let connector = TlsConnector::builder()
-        .min_protocol_version(Some(Protocol::Tlsv12))
-        .max_protocol_version(Some(Protocol::Tlsv12))
-        .add_root_certificate(native_cert.clone())
-        .danger_accept_invalid_hostnames(true)
-        .use_sni(false)
-        .build()?;
-
     Builder::new()
         .filter_level(LevelFilter::Warn)
         .filter_module("actix_web::middleware::logger", LevelFilter::Info)
+        .filter_module("green_site_backend::db", LevelFilter::Info)
+        .filter_module("green_site_backend::token", LevelFilter::Info)
+        .filter_module("green_site_backend::rate_limit", LevelFilter::Info)
+        .filter_module("green_site_backend::tls", LevelFilter::Info)
         .init();
 
-    HttpServer::new(move || {
+    let backend_vars = BackendVars::new()?;
+    let sqlite_pool = db::create_sqlite_pool(&backend_vars).await?;
+    db::run_migrations(&sqlite_pool, &backend_vars).await?;
+
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        return Ok(());
+    }
+
+    if backend_vars.accept_invalid_hostnames() {
+        warn!(
+            "TLS_ACCEPT_INVALID_HOSTNAMES is enabled. FTPS, IMAP and Data Historian connections don't verify \
+             hostnames, so anyone with a certificate from the root CA can impersonate those servers"
+        );
+    }
+
+    security_log::init(&backend_vars, &sqlite_pool)?;
+    let login_limiter = login_limit::LoginLimiter::new(&sqlite_pool, &backend_vars).await?;
+    let listeners = listener::listeners(&backend_vars)?;
+    let tls_policy = tls_policy::TlsPolicy::from_vars(&backend_vars)?;
+    let trusted_roots = tls::TrustedRoots::load(&backend_vars, tls_policy)?;
+    let credential_backend = credentials::from_vars(&backend_vars, &trusted_roots)?;
+    let token_mode = token::TokenMode::from_vars(&backend_vars)?;
+    let file_endpoints = api::FileEndpoints::from_vars(&backend_vars)?;
+    let auth_cookies = backend_vars.auth_cookies.unwrap_or(false);
+    let cert_resolver = Arc::new(tls::CertResolver::load(&backend_vars)?);
+    let client_verifier = match client_cert::client_certs_required(&backend_vars) {
+        true => Some(Arc::new(client_cert::ClientVerifier::load(&backend_vars)?)),
+        false => None,
+    };
+    let tls_config = tls::server_config(cert_resolver.clone(), client_verifier.clone())?;
+    let http_redirect_port = backend_vars.http_redirect_port;
+    let http_redirect_host = backend_vars.http_redirect_host.clone();
+    let mysql_pool = create_pool(&backend_vars, tls_policy);
+
+    tls::spawn_reloader(
+        backend_vars.clone(),
+        cert_resolver,
+        trusted_roots.clone(),
+        client_verifier,
+    )?;
+
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::new(
                 r#"%{r}a "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
             ))
             .wrap(middleware::NormalizePath::new(TrailingSlash::Trim))
             .app_data(backend_vars.clone())
+            .app_data(login_limiter.clone())
+            .app_data(credential_backend.clone())
+            .app_data(token_mode.clone())
             .app_data(mysql_pool.clone())
-            .app_data(smtp_cert.clone())
-            .app_data(native_cert.clone())
-            .app_data(connector.clone())
-            .service(web::scope("/api").configure(api::endpoint_config))
+            .app_data(sqlite_pool.clone())
+            .app_data(trusted_roots.clone())
+            .service(
+                web::scope("/api")
+                    .wrap(Condition::new(auth_cookies, csrf::CsrfProtection))
+                    .configure(|cfg| api::endpoint_config(cfg, file_endpoints)),
+            )
     })
-    .bind(format!("127.0.0.1:{port}"))?
-    .run()
-    .await?;
+    .on_connect(client_cert::store_client_certificate);
+
+    for listener in &listeners {
+        server = match listener {
+            Listener::Tcp { addr, tls: true } => server.bind_rustls(addr, tls_config.clone())?,
+            Listener::Tcp { addr, tls: false } => server.bind(addr)?,
+            Listener::Unix(path) => server.bind_uds(path)?,
+        };
+    }
+
+    let server = server.run();
+
+    match (http_redirect_port, listener::first_https_addr(&listeners)) {
+        (Some(http_port), Some(https_addr)) => {
+            let authority = tls::redirect_authority(http_redirect_host.as_deref(), https_addr)?;
+            let redirect_server = HttpServer::new(move || {
+                let authority = authority.clone();
+
+                App::new().default_service(web::to(move |req: HttpRequest| {
+                    let authority = authority.clone();
+
+                    async move { tls::redirect_to_https(&req, &authority) }
+                }))
+            })
+            .bind((https_addr.ip(), http_port))?
+            .run();
+
+            future::try_join(server, redirect_server).await?;
+        }
+        (Some(_), None) => {
+            return Err("HTTP_REDIRECT_PORT requires an HTTPS listener in WEB_SERVER_BIND".into())
+        }
+        (None, _) => server.await?,
+    }
 
     Ok(())
 }
@@ -0,0 +1,192 @@
+use std::sync::OnceLock;
+
+use actix_web::rt::task;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rand::rngs::OsRng;
+use subtle::ConstantTimeEq;
+
+use crate::{env_vars::BackendVars, error::PasswordError};
+
+const DEFAULT_MIN_LENGTH: usize = 8;
+const DEFAULT_MIN_CHARACTER_CLASSES: u8 = 1;
+
+/// Every hash made by [`hash_password`] is a PHC string starting with this prefix.
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+
+/// The result of checking a password against what's stored in the users table.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Verification {
+    Invalid,
+    Valid,
+    /// The password is correct, but the stored row is still legacy plaintext and should be re-hashed.
+    ValidLegacy,
+}
+
+/// Hashes the password into a salted Argon2id PHC string.
+fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+/// Verifies the password against a stored Argon2id hash, or against a legacy plaintext row in constant time.
+fn verify(password: &str, stored: &str) -> Verification {
+    if stored.starts_with(ARGON2ID_PREFIX) {
+        let is_valid = PasswordHash::new(stored)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false);
+
+        match is_valid {
+            true => Verification::Valid,
+            false => Verification::Invalid,
+        }
+    } else {
+        match bool::from(password.as_bytes().ct_eq(stored.as_bytes())) {
+            true => Verification::ValidLegacy,
+            false => Verification::Invalid,
+        }
+    }
+}
+
+/// The hash of a random password, made on first use. Logins of unknown or disabled users are checked against it so
+/// they take as long as logins of real users.
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    DUMMY_HASH.get_or_init(|| hash(SaltString::generate(&mut OsRng).as_str()).unwrap_or_default())
+}
+
+/// Hashes the password into a salted Argon2id PHC string. Hashing takes long enough to stall every other request
+/// on the worker, so it's done on the blocking thread pool.
+pub(crate) async fn hash_password(password: &str) -> Result<String, PasswordError> {
+    let password = password.to_string();
+
+    Ok(task::spawn_blocking(move || hash(&password)).await??)
+}
+
+/// Verifies the password against a stored Argon2id hash, or against a legacy plaintext row in constant time. Runs
+/// on the blocking thread pool like [`hash_password`].
+pub(crate) async fn verify_password(
+    password: &str,
+    stored: &str,
+) -> Result<Verification, PasswordError> {
+    let (password, stored) = (password.to_string(), stored.to_string());
+
+    Ok(task::spawn_blocking(move || verify(&password, &stored)).await?)
+}
+
+/// Spends as long as [`verify_password`] does on a real Argon2id hash, without anything to match.
+pub(crate) async fn verify_dummy_password(password: &str) -> Result<(), PasswordError> {
+    let password = password.to_string();
+
+    task::spawn_blocking(move || {
+        verify(&password, dummy_hash());
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// The strength new passwords must have, set by ``PASSWORD_MIN_LENGTH`` and ``PASSWORD_MIN_CHARACTER_CLASSES``.
+/// The character classes are lowercase letters, uppercase letters, digits and everything else.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StrengthPolicy {
+    min_length: usize,
+    min_character_classes: u8,
+}
+
+impl StrengthPolicy {
+    pub fn new(vars: &BackendVars) -> Self {
+        Self {
+            min_length: vars.password_min_length.unwrap_or(DEFAULT_MIN_LENGTH),
+            min_character_classes: vars
+                .password_min_character_classes
+                .unwrap_or(DEFAULT_MIN_CHARACTER_CLASSES),
+        }
+    }
+
+    /// Describes why the new password of the user isn't strong enough, if it isn't.
+    pub fn check(&self, username: &str, password: &str) -> Option<String> {
+        let character_classes = [
+            password.bytes().any(|b| b.is_ascii_lowercase()),
+            password.bytes().any(|b| b.is_ascii_uppercase()),
+            password.bytes().any(|b| b.is_ascii_digit()),
+            password.bytes().any(|b| !b.is_ascii_alphanumeric()),
+        ]
+        .into_iter()
+        .filter(|&has_class| has_class)
+        .count() as u8;
+
+        if password.chars().count() < self.min_length {
+            Some(format!(
+                "Password must be at least {} characters.",
+                self.min_length
+            ))
+        } else if character_classes < self.min_character_classes {
+            Some(format!(
+                "Password must contain at least {} of lowercase letters, uppercase letters, digits and symbols.",
+                self.min_character_classes
+            ))
+        } else if password.eq_ignore_ascii_case(username) {
+            Some("Password must not be the username.".to_string())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn verifies_argon2id_hashes() {
+        let hash = hash_password("correct horse").await.unwrap();
+
+        assert!(hash.starts_with(ARGON2ID_PREFIX));
+        assert_eq!(
+            verify_password("correct horse", &hash).await.unwrap(),
+            Verification::Valid
+        );
+        assert_eq!(
+            verify_password("wrong horse", &hash).await.unwrap(),
+            Verification::Invalid
+        );
+    }
+
+    #[actix_web::test]
+    async fn flags_legacy_plaintext_for_rehashing() {
+        assert_eq!(
+            verify_password("hunter22", "hunter22").await.unwrap(),
+            Verification::ValidLegacy
+        );
+        assert_eq!(
+            verify_password("hunter23", "hunter22").await.unwrap(),
+            Verification::Invalid
+        );
+        // A plaintext password that looks like a hash prefix is still never compared as plaintext.
+        assert_eq!(
+            verify_password(ARGON2ID_PREFIX, ARGON2ID_PREFIX)
+                .await
+                .unwrap(),
+            Verification::Invalid
+        );
+    }
+
+    #[actix_web::test]
+    async fn dummy_hash_matches_nothing() {
+        verify_dummy_password("anything").await.unwrap();
+
+        assert!(dummy_hash().starts_with(ARGON2ID_PREFIX));
+        assert_eq!(verify(dummy_hash(), dummy_hash()), Verification::Invalid);
+    }
+}
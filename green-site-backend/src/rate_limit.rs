@@ -0,0 +1,274 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header,
+    Error, HttpResponse,
+};
+use futures::future::{self, LocalBoxFuture, Ready};
+use log::{debug, info, warn};
+
+use crate::{error::ErrorResponse, listener};
+
+/// How many peers are tracked by a limiter before full buckets are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A token bucket policy. Each peer starts with ``capacity`` tokens, every request takes one, and one token is
+/// refilled every ``refill_every``.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RateLimitPolicy {
+    pub capacity: u32,
+    pub refill_every: Duration,
+}
+
+impl RateLimitPolicy {
+    /// For form submissions and file uploads. Bursts of 5 requests and 5 requests a minute after that.
+    pub const STRICT: Self = Self {
+        capacity: 5,
+        refill_every: Duration::from_secs(12),
+    };
+
+    /// For all other applicable endpoints. Bursts of 60 requests and 1 request a second after that.
+    pub const DEFAULT: Self = Self {
+        capacity: 60,
+        refill_every: Duration::from_secs(1),
+    };
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+type Buckets = Arc<Mutex<HashMap<String, Bucket>>>;
+
+/// Limiter state shared by every worker, keyed by the limiter name.
+fn limiter_registry() -> &'static Mutex<HashMap<&'static str, Buckets>> {
+    static REGISTRY: OnceLock<Mutex<HashMap<&'static str, Buckets>>> = OnceLock::new();
+
+    REGISTRY.get_or_init(Default::default)
+}
+
+/// Middleware rate limiting requests by peer address with a token bucket. Limiters with the same name share their
+/// buckets across all workers, so wrapping a scope in every worker's app still gives one limit per peer.
+#[derive(Clone)]
+pub(crate) struct RateLimit {
+    name: &'static str,
+    policy: RateLimitPolicy,
+    buckets: Buckets,
+}
+
+impl RateLimit {
+    pub fn new(name: &'static str, policy: RateLimitPolicy) -> Self {
+        let mut registry = limiter_registry().lock().unwrap_or_else(|e| e.into_inner());
+        let buckets = registry
+            .entry(name)
+            .or_insert_with(|| {
+                info!("Created rate limiter {name} with {policy:?}");
+
+                Default::default()
+            })
+            .clone();
+
+        Self {
+            name,
+            policy,
+            buckets,
+        }
+    }
+
+    /// Takes a token from the peer's bucket. If the bucket is empty, returns how long until a token is refilled.
+    fn take(&self, peer: &str) -> Result<f64, Duration> {
+        self.take_at(peer, Instant::now())
+    }
+
+    /// [`Self::take`] at the given time.
+    fn take_at(&self, peer: &str, now: Instant) -> Result<f64, Duration> {
+        let capacity = self.policy.capacity as f64;
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+
+        if buckets.len() > PRUNE_THRESHOLD {
+            let refill_every = self.policy.refill_every.as_secs_f64();
+
+            buckets.retain(|_, b| {
+                b.tokens + now.duration_since(b.last_refill).as_secs_f64() / refill_every < capacity
+            });
+            info!(
+                "Pruned full buckets of rate limiter {}. {} peers tracked",
+                self.name,
+                buckets.len()
+            );
+        }
+
+        let bucket = buckets.entry(peer.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let refilled = now.duration_since(bucket.last_refill).as_secs_f64()
+            / self.policy.refill_every.as_secs_f64();
+
+        bucket.tokens = (bucket.tokens + refilled).min(capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+
+            Ok(bucket.tokens)
+        } else {
+            Err(self.policy.refill_every.mul_f64(1.0 - bucket.tokens))
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(RateLimitMiddleware {
+            service,
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub(crate) struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: RateLimit,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let peer = match listener::client_addr(req.request()) {
+            Some(peer) => peer,
+            None => {
+                let res = listener::unknown_client();
+
+                return Box::pin(future::ready(Ok(req
+                    .into_response(res)
+                    .map_into_right_body())));
+            }
+        };
+
+        match self.limiter.take(&peer) {
+            Ok(tokens_left) => {
+                debug!(
+                    "Rate limiter {} has {tokens_left:.1} tokens left for {peer}",
+                    self.limiter.name
+                );
+
+                let fut = self.service.call(req);
+
+                Box::pin(async move { fut.await.map(ServiceResponse::map_into_left_body) })
+            }
+            Err(retry_after) => {
+                let retry_secs = retry_after.as_secs_f64().ceil() as u64;
+
+                warn!(
+                    "Rate limiter {} rejected {peer} for {} {}. Retry after {retry_secs}s",
+                    self.limiter.name,
+                    req.method(),
+                    req.path(),
+                );
+
+                let res = HttpResponse::TooManyRequests()
+                    .insert_header((header::RETRY_AFTER, retry_secs.max(1).to_string()))
+                    .json(ErrorResponse {
+                        error: "Too many requests. Please try again later.".to_string(),
+                    });
+
+                Box::pin(future::ready(Ok(req
+                    .into_response(res)
+                    .map_into_right_body())))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POLICY: RateLimitPolicy = RateLimitPolicy {
+        capacity: 3,
+        refill_every: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn buckets_start_full_and_run_out() {
+        let limiter = RateLimit::new("test_run_out", POLICY);
+        let now = Instant::now();
+
+        assert_eq!(limiter.take_at("10.0.0.1", now), Ok(2.0));
+        assert_eq!(limiter.take_at("10.0.0.1", now), Ok(1.0));
+        assert_eq!(limiter.take_at("10.0.0.1", now), Ok(0.0));
+        assert_eq!(
+            limiter.take_at("10.0.0.1", now),
+            Err(Duration::from_secs(10))
+        );
+        // Every peer has its own bucket.
+        assert_eq!(limiter.take_at("10.0.0.2", now), Ok(2.0));
+    }
+
+    #[test]
+    fn buckets_refill_over_time_up_to_capacity() {
+        let limiter = RateLimit::new("test_refill", POLICY);
+        let now = Instant::now();
+
+        for _ in 0..POLICY.capacity {
+            limiter.take_at("10.0.0.1", now).unwrap();
+        }
+
+        assert_eq!(
+            limiter.take_at("10.0.0.1", now + Duration::from_secs(4)),
+            Err(Duration::from_secs(6))
+        );
+        assert_eq!(
+            limiter.take_at("10.0.0.1", now + Duration::from_secs(10)),
+            Ok(0.0)
+        );
+
+        let idle = now + Duration::from_secs(600);
+
+        assert_eq!(limiter.take_at("10.0.0.1", idle), Ok(2.0));
+    }
+
+    #[test]
+    fn limiters_with_the_same_name_share_buckets() {
+        let now = Instant::now();
+
+        RateLimit::new("test_shared", POLICY)
+            .take_at("10.0.0.1", now)
+            .unwrap();
+
+        assert_eq!(
+            RateLimit::new("test_shared", POLICY).take_at("10.0.0.1", now),
+            Ok(1.0)
+        );
+    }
+}
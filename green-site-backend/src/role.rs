@@ -0,0 +1,45 @@
+use serde::{Deserialize, Serialize};
+
+/// The role of a user as stored in the ``role`` column of the users table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub(crate) enum Role {
+    Admin,
+    Operator,
+    Viewer,
+}
+
+/// Something a privileged endpoint requires the caller's role to be allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Permission {
+    ReadSolar,
+    ListFiles,
+    DownloadFiles,
+    ReadEmails,
+    ManageSessions,
+    ManageUsers,
+}
+
+impl Role {
+    pub fn has_permission(self, permission: Permission) -> bool {
+        use Permission::*;
+
+        match self {
+            Role::Admin => true,
+            Role::Operator => matches!(permission, ReadSolar | ListFiles),
+            Role::Viewer => matches!(permission, ReadSolar),
+        }
+    }
+}
+
+impl Permission {
+    /// Whether the request must also come with a client certificate of the same user when ``REQUIRE_CLIENT_CERTS`` is
+    /// enabled, so a stolen token alone can't read files or mail.
+    pub fn requires_client_cert(self) -> bool {
+        matches!(
+            self,
+            Permission::ListFiles | Permission::DownloadFiles | Permission::ReadEmails
+        )
+    }
+}
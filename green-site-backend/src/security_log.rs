@@ -0,0 +1,223 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
+    process,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use actix_web::rt;
+use log::error;
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::env_vars::BackendVars;
+
+/// Rotating file sink limits. ``security.log`` is rotated to ``security.log.1`` and so on up to ``MAX_ROTATED_FILES``.
+const MAX_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_ROTATED_FILES: u32 = 5;
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// Facility ``auth`` (4) with severity ``warning`` (4).
+const SYSLOG_PRIORITY: u8 = 4 * 8 + 4;
+
+/// Why an authentication or authorization attempt failed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum AuthFailureReason {
+    BadCredentials,
+    BadTotpCode,
+    RateLimited,
+    MissingToken,
+    InvalidToken,
+    InsufficientPermission,
+    BadCsrfToken,
+    BadClientCertificate,
+}
+
+impl AuthFailureReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthFailureReason::BadCredentials => "bad_credentials",
+            AuthFailureReason::BadTotpCode => "bad_totp_code",
+            AuthFailureReason::RateLimited => "rate_limited",
+            AuthFailureReason::MissingToken => "missing_token",
+            AuthFailureReason::InvalidToken => "invalid_token",
+            AuthFailureReason::InsufficientPermission => "insufficient_permission",
+            AuthFailureReason::BadCsrfToken => "bad_csrf_token",
+            AuthFailureReason::BadClientCertificate => "bad_client_certificate",
+        }
+    }
+}
+
+/// One structured security event. Must never contain secrets such as passwords or tokens.
+#[derive(Serialize)]
+struct SecurityEvent<'a> {
+    timestamp: u64,
+    event: &'static str,
+    peer_addr: Option<&'a str>,
+    username: Option<&'a str>,
+    reason: &'static str,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self { path, file, size })
+    }
+
+    fn rotated_path(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+
+        path.push(format!(".{n}"));
+        path.into()
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size + line.len() as u64 + 1 > MAX_FILE_SIZE {
+            for n in (1..MAX_ROTATED_FILES).rev() {
+                let from = self.rotated_path(n);
+
+                if from.exists() {
+                    fs::rename(from, self.rotated_path(n + 1))?;
+                }
+            }
+
+            fs::rename(&self.path, self.rotated_path(1))?;
+            *self = Self::open(self.path.clone())?;
+        }
+
+        writeln!(self.file, "{line}")?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(Mutex<RotatingFile>),
+    Syslog(UnixDatagram),
+    Sqlite(SqlitePool),
+}
+
+impl Sink {
+    /// Parses ``SECURITY_LOG_SINK``, which is ``stderr``, ``file:<PATH>``, ``syslog`` or ``sqlite``.
+    fn from_config(config: &str, pool: &SqlitePool) -> io::Result<Self> {
+        match config {
+            "stderr" => Ok(Sink::Stderr),
+            "sqlite" => Ok(Sink::Sqlite(pool.clone())),
+            "syslog" => {
+                let socket = UnixDatagram::unbound()?;
+
+                socket.connect(SYSLOG_SOCKET)?;
+
+                Ok(Sink::Syslog(socket))
+            }
+            _ => match config.strip_prefix("file:") {
+                Some(path) if !path.is_empty() => {
+                    Ok(Sink::File(Mutex::new(RotatingFile::open(path.into())?)))
+                }
+                _ => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "Bad SECURITY_LOG_SINK {config:?}. Expected stderr, file:<PATH>, syslog or sqlite"
+                    ),
+                )),
+            },
+        }
+    }
+
+    fn write(&self, event: &SecurityEvent, line: &str) -> io::Result<()> {
+        match self {
+            Sink::Stderr => writeln!(io::stderr().lock(), "{line}"),
+            Sink::File(file) => file
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .write_line(line),
+            Sink::Syslog(socket) => socket
+                .send(
+                    format!(
+                        "<{SYSLOG_PRIORITY}>green-site-backend[{}]: {line}",
+                        process::id()
+                    )
+                    .as_bytes(),
+                )
+                .map(|_| ()),
+            Sink::Sqlite(pool) => {
+                let pool = pool.clone();
+                let query = sqlx::query(
+                    "INSERT INTO audit_log (timestamp, event, peer_addr, username, reason) \
+                     VALUES (?, ?, ?, ?, ?);",
+                )
+                .bind(event.timestamp as i64)
+                .bind(event.event)
+                .bind(event.peer_addr.map(str::to_string))
+                .bind(event.username.map(str::to_string))
+                .bind(event.reason);
+                let line = line.to_string();
+
+                rt::spawn(async move {
+                    if let Err(err) = query.execute(&pool).await {
+                        error!("Couldn't write security event: {err}. Event: {line}");
+                    }
+                });
+
+                Ok(())
+            }
+        }
+    }
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Opens the sink configured by ``SECURITY_LOG_SINK``. Events go to stderr if it's unset or this was never called.
+pub(crate) fn init(vars: &BackendVars, pool: &SqlitePool) -> io::Result<()> {
+    let sink = match &vars.security_log_sink {
+        Some(config) => Sink::from_config(config, pool)?,
+        None => Sink::Stderr,
+    };
+
+    // Only fails if already initialized, in which case the first sink is kept.
+    let _ = SINK.set(sink);
+
+    Ok(())
+}
+
+/// Records a failed authentication or authorization attempt as a JSON line.
+pub(crate) fn auth_failure(
+    peer_addr: Option<&str>,
+    username: Option<&str>,
+    reason: AuthFailureReason,
+) {
+    let event = SecurityEvent {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        event: "auth_failure",
+        peer_addr,
+        username,
+        reason: reason.as_str(),
+    };
+    let line = match serde_json::to_string(&event) {
+        Ok(line) => line,
+        Err(err) => {
+            error!("Couldn't serialize security event: {err}");
+
+            return;
+        }
+    };
+
+    if let Err(err) = SINK.get_or_init(|| Sink::Stderr).write(&event, &line) {
+        error!("Couldn't write security event: {err}. Event: {line}");
+    }
+}
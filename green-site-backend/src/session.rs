@@ -0,0 +1,205 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{role::Role, token::SessionToken};
+
+/// How long a session token stays valid after it was issued.
+const SESSION_LIFETIME: Duration = Duration::from_secs(8 * 60 * 60);
+const TOKEN_BYTES: usize = 32;
+/// How long a user has to enter their TOTP code after their password was accepted.
+const MFA_CHALLENGE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// A login session as stored in the SQLite DB along with its user's role. The token itself is never stored, only its hash.
+/// JWTs aren't stored, so their sessions have no ID.
+#[derive(Debug, FromRow)]
+pub(crate) struct Session {
+    pub id: Option<i64>,
+    pub username: String,
+    pub role: Role,
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn hash_token(token: &str) -> String {
+    to_hex(&Sha256::digest(token.as_bytes()))
+}
+
+/// A random token of ``TOKEN_BYTES`` bytes encoded as hex.
+pub(crate) fn random_token() -> String {
+    to_hex(&rand::thread_rng().gen::<[u8; TOKEN_BYTES]>())
+}
+
+/// Mints a new random token for the user and stores its session. Expired sessions are purged along the way.
+pub(crate) async fn create_session(
+    pool: &SqlitePool,
+    username: &str,
+) -> sqlx::Result<SessionToken> {
+    let token = random_token();
+    let issued_at = unix_now();
+    let expires_at = issued_at + SESSION_LIFETIME.as_secs() as i64;
+
+    sqlx::query("DELETE FROM sessions WHERE expires_at <= ?;")
+        .bind(issued_at)
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO sessions (token_hash, username, issued_at, expires_at) VALUES (?, ?, ?, ?);",
+    )
+    .bind(hash_token(&token))
+    .bind(username)
+    .bind(issued_at)
+    .bind(expires_at)
+    .execute(pool)
+    .await?;
+
+    Ok(SessionToken::new(token))
+}
+
+/// Looks up the unexpired session the token belongs to, if any.
+pub(crate) async fn find_session(pool: &SqlitePool, token: &str) -> sqlx::Result<Option<Session>> {
+    sqlx::query_as(
+        "SELECT sessions.id, sessions.username, users.role FROM sessions \
+         JOIN users ON users.username = sessions.username \
+         WHERE sessions.token_hash=? AND sessions.expires_at > ? AND NOT users.disabled;",
+    )
+    .bind(hash_token(token))
+    .bind(unix_now())
+    .fetch_optional(pool)
+    .await
+}
+
+/// An unexpired session as listed to admins.
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct SessionInfo {
+    pub id: i64,
+    pub username: String,
+    pub issued_at: i64,
+    pub expires_at: i64,
+}
+
+/// Lists every unexpired session, oldest first.
+pub(crate) async fn list_sessions(pool: &SqlitePool) -> sqlx::Result<Vec<SessionInfo>> {
+    sqlx::query_as(
+        "SELECT id, username, issued_at, expires_at FROM sessions \
+         WHERE expires_at > ? ORDER BY issued_at;",
+    )
+    .bind(unix_now())
+    .fetch_all(pool)
+    .await
+}
+
+/// Revokes the session the token belongs to. Returns whether a session was revoked.
+pub(crate) async fn revoke_token(pool: &SqlitePool, token: &str) -> sqlx::Result<bool> {
+    let result = sqlx::query("DELETE FROM sessions WHERE token_hash=?;")
+        .bind(hash_token(token))
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes the session with the ID. Returns whether a session was revoked.
+pub(crate) async fn revoke_session(pool: &SqlitePool, id: i64) -> sqlx::Result<bool> {
+    let result = sqlx::query("DELETE FROM sessions WHERE id=?;")
+        .bind(id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Revokes every session. Returns how many sessions were revoked.
+pub(crate) async fn revoke_all_sessions(pool: &SqlitePool) -> sqlx::Result<u64> {
+    let result = sqlx::query("DELETE FROM sessions;").execute(pool).await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Revokes every session of the user. Returns how many sessions were revoked.
+pub(crate) async fn revoke_user_sessions(pool: &SqlitePool, username: &str) -> sqlx::Result<u64> {
+    let result = sqlx::query("DELETE FROM sessions WHERE username=?;")
+        .bind(username)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Revokes every session of the user except the one with the ID. Returns how many sessions were revoked.
+pub(crate) async fn revoke_other_user_sessions(
+    pool: &SqlitePool,
+    username: &str,
+    keep_id: i64,
+) -> sqlx::Result<u64> {
+    let result = sqlx::query("DELETE FROM sessions WHERE username=? AND id != ?;")
+        .bind(username)
+        .bind(keep_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Mints a short-lived challenge for a user whose password was accepted but who still has to enter a TOTP code.
+pub(crate) async fn create_mfa_challenge(
+    pool: &SqlitePool,
+    username: &str,
+) -> sqlx::Result<String> {
+    let challenge = random_token();
+    let now = unix_now();
+
+    sqlx::query("DELETE FROM mfa_challenges WHERE expires_at <= ?;")
+        .bind(now)
+        .execute(pool)
+        .await?;
+    sqlx::query(
+        "INSERT INTO mfa_challenges (challenge_hash, username, expires_at) VALUES (?, ?, ?);",
+    )
+    .bind(hash_token(&challenge))
+    .bind(username)
+    .bind(now + MFA_CHALLENGE_LIFETIME.as_secs() as i64)
+    .execute(pool)
+    .await?;
+
+    Ok(challenge)
+}
+
+/// Looks up the username and role of the user the unexpired challenge was issued for, if any.
+pub(crate) async fn find_mfa_challenge(
+    pool: &SqlitePool,
+    challenge: &str,
+) -> sqlx::Result<Option<(String, Role)>> {
+    sqlx::query_as(
+        "SELECT mfa_challenges.username, users.role FROM mfa_challenges \
+         JOIN users ON users.username = mfa_challenges.username \
+         WHERE mfa_challenges.challenge_hash=? AND mfa_challenges.expires_at > ? \
+         AND NOT users.disabled;",
+    )
+    .bind(hash_token(challenge))
+    .bind(unix_now())
+    .fetch_optional(pool)
+    .await
+}
+
+/// Deletes the challenge once it has been answered.
+pub(crate) async fn delete_mfa_challenge(pool: &SqlitePool, challenge: &str) -> sqlx::Result<()> {
+    sqlx::query("DELETE FROM mfa_challenges WHERE challenge_hash=?;")
+        .bind(hash_token(challenge))
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,370 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{
+    http::header,
+    rt::{
+        self,
+        signal::unix::{signal, SignalKind},
+        time,
+    },
+    HttpRequest, HttpResponse,
+};
+use lettre::transport::smtp::client::Certificate as SmtpCertificate;
+use log::{error, info};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert},
+    sign::{self, CertifiedKey},
+    Certificate, ClientConfig, PrivateKey, ServerConfig,
+};
+use rustls_pemfile::Item;
+
+use crate::{
+    client_cert::{self, ClientVerifier},
+    env_vars::BackendVars,
+    error::CertConfigError,
+    tls_policy::{TlsPolicy, CIPHER_SUITES, PROTOCOL_VERSIONS},
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+fn open_pem(path: &str) -> Result<BufReader<File>, CertConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| CertConfigError::ReadPemIoError(path.to_string(), e))
+}
+
+/// Reads the certificate chain from ``SSL_CERTIFICATE_PEM_PATH``.
+fn load_certificate_chain(vars: &BackendVars) -> Result<Vec<Certificate>, CertConfigError> {
+    let path = vars.ssl_certificate_pem_path.as_str();
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?)
+        .map_err(|e| CertConfigError::ReadPemIoError(path.to_string(), e))?;
+
+    if certs.is_empty() {
+        return Err(CertConfigError::BadServerCertificate(format!(
+            "No certificates in {path}"
+        )));
+    }
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PKCS#8, RSA or SEC1 private key from ``SSL_PRIVATE_KEY_PEM_PATH``.
+fn load_private_key(vars: &BackendVars) -> Result<PrivateKey, CertConfigError> {
+    let path = vars.ssl_private_key_pem_path.as_str();
+    let mut reader = open_pem(path)?;
+
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| CertConfigError::ReadPemIoError(path.to_string(), e))?
+        {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => {}
+            None => {
+                return Err(CertConfigError::BadServerCertificate(format!(
+                    "No private key in {path}"
+                )))
+            }
+        }
+    }
+}
+
+fn load_certified_key(vars: &BackendVars) -> Result<CertifiedKey, CertConfigError> {
+    let key = sign::any_supported_type(&load_private_key(vars)?).map_err(|_| {
+        CertConfigError::BadServerCertificate(format!(
+            "Unsupported private key type in {}",
+            vars.ssl_private_key_pem_path
+        ))
+    })?;
+
+    Ok(CertifiedKey::new(load_certificate_chain(vars)?, key))
+}
+
+/// Hands out the current server certificate to each new TLS handshake. Connections that are already established keep
+/// the certificate they were made with.
+pub(crate) struct CertResolver(RwLock<Arc<CertifiedKey>>);
+
+impl CertResolver {
+    pub fn load(vars: &BackendVars) -> Result<Self, CertConfigError> {
+        Ok(Self(RwLock::new(Arc::new(load_certified_key(vars)?))))
+    }
+
+    fn replace(&self, key: CertifiedKey) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(key);
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap_or_else(|e| e.into_inner()).clone())
+    }
+}
+
+/// Creates the config of the HTTPS listener, whose certificate comes from the resolver. Client certificates are
+/// requested if there's a client verifier.
+pub(crate) fn server_config(
+    resolver: Arc<CertResolver>,
+    client_verifier: Option<Arc<ClientVerifier>>,
+) -> Result<ServerConfig, CertConfigError> {
+    let builder = ServerConfig::builder()
+        .with_cipher_suites(CIPHER_SUITES)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(PROTOCOL_VERSIONS)?;
+    let builder = match client_verifier {
+        Some(client_verifier) => builder.with_client_cert_verifier(client_verifier),
+        None => builder.with_no_client_auth(),
+    };
+
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+/// Reads the root certificates from ``ROOT_CERTIFICATE_PATH`` as DER, and the SMTP certificate of the whole file.
+fn get_trusted_roots(
+    vars: &BackendVars,
+) -> Result<(Vec<Certificate>, SmtpCertificate), CertConfigError> {
+    use CertConfigError::*;
+
+    let root_cert_path = vars.root_certificate_path.as_str();
+    let mut root_cert_file = match File::open(root_cert_path) {
+        Ok(ok) => ok,
+        Err(err) => return Err(ReadPemIoError(root_cert_path.to_string(), err)),
+    };
+    let mut root_cert_bytes = Vec::new();
+    root_cert_file
+        .read_to_end(&mut root_cert_bytes)
+        .map_err(|e| ReadPemIoError(root_cert_path.to_string(), e))?;
+    let certs = rustls_pemfile::certs(&mut root_cert_bytes.as_slice())
+        .map_err(|e| ReadPemIoError(root_cert_path.to_string(), e))?;
+
+    if certs.is_empty() {
+        return Err(BadServerCertificate(format!(
+            "No root certificates in {root_cert_path}"
+        )));
+    }
+
+    Ok((
+        certs.into_iter().map(Certificate).collect(),
+        SmtpCertificate::from_pem(&root_cert_bytes)?,
+    ))
+}
+
+struct Roots {
+    client_config: Arc<ClientConfig>,
+    ldap_config: Arc<ldap_rustls::ClientConfig>,
+    smtp_cert: SmtpCertificate,
+}
+
+impl Roots {
+    fn load(vars: &BackendVars, policy: TlsPolicy) -> Result<Self, CertConfigError> {
+        let (certs, smtp_cert) = get_trusted_roots(vars)?;
+
+        Ok(Self {
+            client_config: Arc::new(policy.client_config(&certs, vars.accept_invalid_hostnames())?),
+            ldap_config: Arc::new(policy.ldap_config(&certs)?),
+            smtp_cert,
+        })
+    }
+}
+
+/// The root certificate from ``ROOT_CERTIFICATE_PATH`` in the forms each client needs, along with the TLS policy of
+/// upstream connections. Shared by every worker, so new connections anywhere use the root certificate once it's
+/// reloaded.
+#[derive(Clone)]
+pub(crate) struct TrustedRoots {
+    roots: Arc<RwLock<Roots>>,
+    policy: TlsPolicy,
+}
+
+impl TrustedRoots {
+    pub fn load(vars: &BackendVars, policy: TlsPolicy) -> Result<Self, CertConfigError> {
+        Ok(Self {
+            roots: Arc::new(RwLock::new(Roots::load(vars, policy)?)),
+            policy,
+        })
+    }
+
+    fn replace(&self, roots: Roots) {
+        *self.roots.write().unwrap_or_else(|e| e.into_inner()) = roots;
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Roots> {
+        self.roots.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn policy(&self) -> TlsPolicy {
+        self.policy
+    }
+
+    /// A client config following the TLS policy and trusting the root certificate for FTPS and IMAP. It verifies
+    /// hostnames unless ``TLS_ACCEPT_INVALID_HOSTNAMES`` is enabled.
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.read().client_config.clone()
+    }
+
+    /// A client config following the TLS policy and trusting the root certificate for LDAP.
+    pub fn ldap_config(&self) -> Arc<ldap_rustls::ClientConfig> {
+        self.read().ldap_config.clone()
+    }
+
+    pub fn smtp_cert(&self) -> SmtpCertificate {
+        self.read().smtp_cert.clone()
+    }
+}
+
+/// Reloads the server certificate and root certificate on SIGHUP or when any of their files change.
+struct Reloader {
+    vars: BackendVars,
+    resolver: Arc<CertResolver>,
+    roots: TrustedRoots,
+    client_verifier: Option<Arc<ClientVerifier>>,
+    last_modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl Reloader {
+    fn modified_times(&self) -> Vec<Option<SystemTime>> {
+        [
+            &self.vars.ssl_certificate_pem_path,
+            &self.vars.ssl_private_key_pem_path,
+            &self.vars.root_certificate_path,
+        ]
+        .into_iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+    }
+
+    /// Loads everything before swapping anything in, so a bad file leaves the old certificates in use.
+    fn reload(&self, trigger: &str) {
+        *self.last_modified.lock().unwrap_or_else(|e| e.into_inner()) = self.modified_times();
+
+        let loaded = load_certified_key(&self.vars).and_then(|key| {
+            let roots = Roots::load(&self.vars, self.roots.policy())?;
+            let client_verifier = match self.client_verifier {
+                Some(_) => Some(client_cert::client_cert_verifier(&self.vars)?),
+                None => None,
+            };
+
+            Ok((key, roots, client_verifier))
+        });
+
+        match loaded {
+            Ok((key, roots, client_verifier)) => {
+                self.resolver.replace(key);
+                self.roots.replace(roots);
+
+                if let (Some(current), Some(loaded)) = (&self.client_verifier, client_verifier) {
+                    current.replace(loaded);
+                }
+
+                info!("Reloaded TLS certificates after {trigger}");
+            }
+            Err(err) => error!(
+                "Couldn't reload TLS certificates after {trigger}: {err}. Still using the old ones"
+            ),
+        }
+    }
+
+    fn has_changed(&self) -> bool {
+        *self.last_modified.lock().unwrap_or_else(|e| e.into_inner()) != self.modified_times()
+    }
+}
+
+/// Spawns the tasks reloading the certificates on SIGHUP and when their files change.
+pub(crate) fn spawn_reloader(
+    vars: BackendVars,
+    resolver: Arc<CertResolver>,
+    roots: TrustedRoots,
+    client_verifier: Option<Arc<ClientVerifier>>,
+) -> io::Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    let reloader = Arc::new(Reloader {
+        vars,
+        resolver,
+        roots,
+        client_verifier,
+        last_modified: Mutex::default(),
+    });
+
+    *reloader
+        .last_modified
+        .lock()
+        .unwrap_or_else(|e| e.into_inner()) = reloader.modified_times();
+
+    let on_hangup = reloader.clone();
+
+    rt::spawn(async move {
+        while hangups.recv().await.is_some() {
+            on_hangup.reload("SIGHUP");
+        }
+    });
+    rt::spawn(async move {
+        let mut interval = time::interval(RELOAD_POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if reloader.has_changed() {
+                reloader.reload("a file change");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// The host and port plain HTTP requests are redirected to. It's never taken from the request, so a forged Host header
+/// can't redirect clients elsewhere. ``HTTP_REDIRECT_HOST`` names the host, defaulting to the IP of the HTTPS listener.
+pub(crate) fn redirect_authority(
+    redirect_host: Option<&str>,
+    https_addr: SocketAddr,
+) -> io::Result<String> {
+    let host = match redirect_host {
+        Some(host) => {
+            let is_hostname = !host.is_empty()
+                && host
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+
+            if !is_hostname {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Bad HTTP_REDIRECT_HOST {host:?}. Expected a hostname"),
+                ));
+            }
+
+            host.to_string()
+        }
+        None if https_addr.ip().is_unspecified() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "HTTP_REDIRECT_HOST must be set when the HTTPS listener's IP is unspecified",
+            ))
+        }
+        None => match https_addr.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        },
+    };
+
+    Ok(format!("{host}:{}", https_addr.port()))
+}
+
+/// Permanently redirects a plain HTTP request to the same path over HTTPS at ``authority``.
+pub(crate) fn redirect_to_https(req: &HttpRequest, authority: &str) -> HttpResponse {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+
+    HttpResponse::PermanentRedirect()
+        .insert_header((header::LOCATION, format!("https://{authority}{path}")))
+        .finish()
+}
@@ -0,0 +1,448 @@
+use std::{io, sync::Arc, time::SystemTime};
+
+use lettre::transport::smtp::client::TlsVersion as SmtpTlsVersion;
+use rustls::{
+    cipher_suite::{
+        TLS13_AES_128_GCM_SHA256, TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256,
+        TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256, TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256, TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    },
+    client::{ServerCertVerified, ServerCertVerifier},
+    version::{TLS12, TLS13},
+    Certificate, ClientConfig, RootCertStore, ServerName, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+use sqlx::{
+    mysql::{MySqlConnectOptions, MySqlConnection, MySqlSslMode},
+    Error as SqlxError,
+};
+
+use crate::{env_vars::BackendVars, error::CertConfigError};
+
+/// The cipher suites allowed for the HTTPS listeners and every upstream connection. Only AEAD suites with forward
+/// secrecy are offered.
+pub(crate) const CIPHER_SUITES: &[SupportedCipherSuite] = &[
+    TLS13_AES_256_GCM_SHA384,
+    TLS13_AES_128_GCM_SHA256,
+    TLS13_CHACHA20_POLY1305_SHA256,
+    TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+];
+
+/// The TLS versions the HTTPS listeners allow.
+pub(crate) const PROTOCOL_VERSIONS: &[&SupportedProtocolVersion] = &[&TLS13, &TLS12];
+
+/// The TLS versions upstream clients offer when ``UPSTREAM_TLS_MIN_VERSION`` is 1.3.
+const TLS13_ONLY: &[&SupportedProtocolVersion] = &[&TLS13];
+
+/// The signature algorithms server certificates may be signed with, the same as rustls allows.
+static SIGNATURE_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+    &webpki::ED25519,
+    &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &webpki::RSA_PKCS1_2048_8192_SHA256,
+    &webpki::RSA_PKCS1_2048_8192_SHA384,
+    &webpki::RSA_PKCS1_2048_8192_SHA512,
+    &webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+/// The oldest TLS version upstream connections may negotiate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl TlsVersion {
+    fn parse(var: &str, version: &str) -> io::Result<Self> {
+        match version {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bad {var} {version:?}. Expected 1.2 or 1.3"),
+            )),
+        }
+    }
+}
+
+fn invalid_certificate(err: webpki::Error) -> rustls::Error {
+    rustls::Error::InvalidCertificateData(format!("invalid peer certificate: {err:?}"))
+}
+
+/// ``CIPHER_SUITES`` for the newer rustls ldap3 is built on.
+fn ldap_cipher_suites() -> Vec<ldap_rustls::SupportedCipherSuite> {
+    ldap_rustls::ALL_CIPHER_SUITES
+        .iter()
+        .filter(|suite| {
+            CIPHER_SUITES
+                .iter()
+                .any(|allowed| allowed.suite().get_u16() == suite.suite().get_u16())
+        })
+        .copied()
+        .collect()
+}
+
+/// Verifies that server certificates chain up to the root certificate without checking the name they're valid for,
+/// for ``TLS_ACCEPT_INVALID_HOSTNAMES``.
+struct AnyHostnameVerifier {
+    roots: Vec<Certificate>,
+}
+
+impl AnyHostnameVerifier {
+    fn new(roots: &[Certificate]) -> Result<Self, CertConfigError> {
+        for root in roots {
+            webpki::TrustAnchor::try_from_cert_der(&root.0)?;
+        }
+
+        Ok(Self {
+            roots: roots.to_vec(),
+        })
+    }
+}
+
+impl ServerCertVerifier for AnyHostnameVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = webpki::EndEntityCert::try_from(end_entity.0.as_slice())
+            .map_err(invalid_certificate)?;
+        let anchors = self
+            .roots
+            .iter()
+            .map(|root| webpki::TrustAnchor::try_from_cert_der(&root.0))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(invalid_certificate)?;
+        let intermediates: Vec<&[u8]> =
+            intermediates.iter().map(|cert| cert.0.as_slice()).collect();
+        let now = webpki::Time::try_from(now).map_err(|_| rustls::Error::FailedToGetCurrentTime)?;
+
+        cert.verify_is_valid_tls_server_cert(
+            SIGNATURE_ALGORITHMS,
+            &webpki::TlsServerTrustAnchors(&anchors),
+            &intermediates,
+            now,
+        )
+        .map_err(invalid_certificate)?;
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The TLS versions and cipher suites allowed for connections to the FTPS, mail, LDAP and Data Historian servers.
+/// Every upstream client is configured from this, offering only ``CIPHER_SUITES``.
+///
+/// There's no maximum version since lettre and sqlx can't limit it. TLS 1.3 is always allowed.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TlsPolicy {
+    min: TlsVersion,
+}
+
+impl TlsPolicy {
+    /// Reads ``UPSTREAM_TLS_MIN_VERSION``, defaulting to 1.2.
+    pub fn from_vars(vars: &BackendVars) -> io::Result<Self> {
+        let min = match vars.upstream_tls_min_version.as_deref() {
+            Some(version) => TlsVersion::parse("UPSTREAM_TLS_MIN_VERSION", version)?,
+            None => TlsVersion::Tls12,
+        };
+
+        Ok(Self { min })
+    }
+
+    /// The versions upstream clients offer.
+    pub fn protocol_versions(&self) -> &'static [&'static SupportedProtocolVersion] {
+        match self.min {
+            TlsVersion::Tls12 => PROTOCOL_VERSIONS,
+            TlsVersion::Tls13 => TLS13_ONLY,
+        }
+    }
+
+    /// A client config for FTPS and IMAP trusting only ``roots``. It verifies hostnames unless
+    /// ``accept_invalid_hostnames`` is set.
+    pub fn client_config(
+        &self,
+        roots: &[Certificate],
+        accept_invalid_hostnames: bool,
+    ) -> Result<ClientConfig, CertConfigError> {
+        let builder = ClientConfig::builder()
+            .with_cipher_suites(CIPHER_SUITES)
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(self.protocol_versions())?;
+        if accept_invalid_hostnames {
+            let verifier = AnyHostnameVerifier::new(roots)?;
+
+            Ok(builder
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth())
+        } else {
+            let mut store = RootCertStore::empty();
+
+            for root in roots {
+                store.add(root)?;
+            }
+
+            Ok(builder.with_root_certificates(store).with_no_client_auth())
+        }
+    }
+
+    /// A client config for LDAP trusting only ``roots``, for the newer rustls ldap3 is built on. LDAP always verifies
+    /// hostnames.
+    pub fn ldap_config(
+        &self,
+        roots: &[Certificate],
+    ) -> Result<ldap_rustls::ClientConfig, CertConfigError> {
+        let versions: &[&ldap_rustls::SupportedProtocolVersion] = match self.min {
+            TlsVersion::Tls12 => &[&ldap_rustls::version::TLS13, &ldap_rustls::version::TLS12],
+            TlsVersion::Tls13 => &[&ldap_rustls::version::TLS13],
+        };
+        let mut store = ldap_rustls::RootCertStore::empty();
+
+        for root in roots {
+            store.add(&ldap_rustls::Certificate(root.0.clone()))?;
+        }
+
+        Ok(ldap_rustls::ClientConfig::builder()
+            .with_cipher_suites(&ldap_cipher_suites())
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(versions)?
+            .with_root_certificates(store)
+            .with_no_client_auth())
+    }
+
+    /// The minimum version for SMTP. lettre offers rustls' default cipher suites, which are ``CIPHER_SUITES``.
+    pub fn smtp_min_version(&self) -> SmtpTlsVersion {
+        match self.min {
+            TlsVersion::Tls12 => SmtpTlsVersion::Tlsv12,
+            TlsVersion::Tls13 => SmtpTlsVersion::Tlsv13,
+        }
+    }
+
+    /// Requires TLS for the Data Historian, trusting ``ROOT_CERTIFICATE_PATH`` and verifying the certificate against
+    /// ``data_historian_server_name``. sqlx offers rustls' default cipher suites, which are ``CIPHER_SUITES``, but
+    /// can't limit versions, so they're checked by ``check_mysql_version`` instead.
+    pub fn mysql_options(
+        &self,
+        options: MySqlConnectOptions,
+        vars: &BackendVars,
+    ) -> MySqlConnectOptions {
+        let ssl_mode = if vars.accept_invalid_hostnames() {
+            MySqlSslMode::VerifyCa
+        } else {
+            MySqlSslMode::VerifyIdentity
+        };
+
+        options
+            .ssl_mode(ssl_mode)
+            .ssl_ca(&vars.root_certificate_path)
+    }
+
+    /// Whether MySQL's ``Ssl_version`` of a connection is allowed.
+    fn allows_mysql_version(&self, version: &str) -> bool {
+        match version {
+            "TLSv1.3" => true,
+            "TLSv1.2" => self.min == TlsVersion::Tls12,
+            _ => false,
+        }
+    }
+
+    /// Refuses a new Data Historian connection that negotiated a version below the minimum. Run after connecting,
+    /// before the connection is used.
+    pub async fn check_mysql_version(self, conn: &mut MySqlConnection) -> Result<(), SqlxError> {
+        let (_, version): (String, String) =
+            sqlx::query_as("SHOW SESSION STATUS LIKE 'Ssl_version'")
+                .fetch_one(conn)
+                .await?;
+
+        if self.allows_mysql_version(&version) {
+            Ok(())
+        } else {
+            Err(SqlxError::Tls(
+                format!(
+                    "Data Historian connection uses {version:?}, below UPSTREAM_TLS_MIN_VERSION"
+                )
+                .into(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rcgen::{BasicConstraints, CertificateParams, IsCa};
+    use rustls::{ClientConnection, PrivateKey, ServerConfig, ServerConnection};
+
+    use super::*;
+
+    fn policy(min: Option<&str>) -> io::Result<TlsPolicy> {
+        TlsPolicy::from_vars(&BackendVars {
+            upstream_tls_min_version: min.map(str::to_string),
+            ..BackendVars::for_tests()
+        })
+    }
+
+    fn suite_ids(suites: &[SupportedCipherSuite]) -> HashSet<u16> {
+        suites.iter().map(|suite| suite.suite().get_u16()).collect()
+    }
+
+    /// A CA, and a certificate for localhost signed by it with its key.
+    fn certificates() -> (Certificate, Certificate, PrivateKey) {
+        let mut ca_params = CertificateParams::new(Vec::new());
+
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+        let ca = rcgen::Certificate::from_params(ca_params).unwrap();
+        let cert =
+            rcgen::Certificate::from_params(CertificateParams::new(vec!["localhost".to_string()]))
+                .unwrap();
+
+        (
+            Certificate(ca.serialize_der().unwrap()),
+            Certificate(cert.serialize_der_with_signer(&ca).unwrap()),
+            PrivateKey(cert.serialize_private_key_der()),
+        )
+    }
+
+    /// A client config following the policy with the minimum version ``min``, trusting ``ca``.
+    fn client(min: Option<&str>, ca: &Certificate, accept_invalid_hostnames: bool) -> ClientConfig {
+        policy(min)
+            .unwrap()
+            .client_config(std::slice::from_ref(ca), accept_invalid_hostnames)
+            .unwrap()
+    }
+
+    /// Runs a handshake in memory between the client config and a server for localhost limited to ``server_versions``.
+    fn handshake(
+        client: ClientConfig,
+        name: &str,
+        (cert, key): &(Certificate, PrivateKey),
+        server_versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<(), rustls::Error> {
+        let server = ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(server_versions)
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key.clone())
+            .unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client), ServerName::try_from(name).unwrap())?;
+        let mut server = ServerConnection::new(Arc::new(server))?;
+
+        while client.is_handshaking() || server.is_handshaking() {
+            let mut buf = Vec::new();
+
+            client.write_tls(&mut buf).unwrap();
+            server.read_tls(&mut buf.as_slice()).unwrap();
+            server.process_new_packets()?;
+
+            buf.clear();
+            server.write_tls(&mut buf).unwrap();
+            client.read_tls(&mut buf.as_slice()).unwrap();
+            client.process_new_packets()?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn parses_the_minimum_version() {
+        assert_eq!(policy(None).unwrap().min, TlsVersion::Tls12);
+        assert_eq!(policy(Some("1.2")).unwrap().min, TlsVersion::Tls12);
+        assert_eq!(policy(Some("1.3")).unwrap().min, TlsVersion::Tls13);
+        assert!(policy(Some("1.1")).is_err());
+        assert!(policy(Some("tls1.3")).is_err());
+    }
+
+    /// lettre and sqlx offer rustls' defaults, which must stay within the allow-list.
+    #[test]
+    fn default_cipher_suites_are_the_allowed_ones() {
+        assert_eq!(
+            suite_ids(rustls::DEFAULT_CIPHER_SUITES),
+            suite_ids(CIPHER_SUITES)
+        );
+    }
+
+    #[test]
+    fn ldap_cipher_suites_are_the_allowed_ones() {
+        let offered: HashSet<u16> = ldap_cipher_suites()
+            .iter()
+            .map(|suite| suite.suite().get_u16())
+            .collect();
+
+        assert_eq!(offered, suite_ids(CIPHER_SUITES));
+    }
+
+    #[test]
+    fn minimum_version_is_enforced() {
+        let (ca, cert, key) = certificates();
+        let server = (cert, key);
+
+        assert!(handshake(client(None, &ca, false), "localhost", &server, &[&TLS12]).is_ok());
+        assert!(handshake(
+            client(Some("1.3"), &ca, false),
+            "localhost",
+            &server,
+            &[&TLS13]
+        )
+        .is_ok());
+        assert!(handshake(
+            client(Some("1.3"), &ca, false),
+            "localhost",
+            &server,
+            &[&TLS12]
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn hostnames_are_verified_unless_accepting_invalid_ones() {
+        let (ca, cert, key) = certificates();
+        let (other_ca, _, _) = certificates();
+        let server = (cert, key);
+        let versions = PROTOCOL_VERSIONS;
+
+        assert!(handshake(client(None, &ca, false), "localhost", &server, versions).is_ok());
+        assert!(handshake(client(None, &ca, false), "example.com", &server, versions).is_err());
+        assert!(handshake(client(None, &ca, true), "example.com", &server, versions).is_ok());
+        // The certificate must still be signed by the root certificate.
+        assert!(handshake(
+            client(None, &other_ca, true),
+            "localhost",
+            &server,
+            versions
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn mysql_versions_below_the_minimum_are_refused() {
+        let tls12 = policy(None).unwrap();
+        let tls13 = policy(Some("1.3")).unwrap();
+
+        assert!(tls12.allows_mysql_version("TLSv1.2"));
+        assert!(tls12.allows_mysql_version("TLSv1.3"));
+        assert!(!tls12.allows_mysql_version("TLSv1.1"));
+        assert!(!tls12.allows_mysql_version(""));
+        assert!(!tls13.allows_mysql_version("TLSv1.2"));
+        assert!(tls13.allows_mysql_version("TLSv1.3"));
+    }
+}
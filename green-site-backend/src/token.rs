@@ -1,39 +1,231 @@
-use actix_web::{http::header, HttpRequest};
+use std::{error::Error, io};
+
+use actix_web::{
+    cookie::{Cookie, SameSite},
+    http::header,
+    HttpRequest,
+};
+use log::{debug, error, info};
 use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{
+    client_cert,
+    env_vars::BackendVars,
+    error::MISSING_APP_DATA,
+    jwt::JwtKeys,
+    listener,
+    role::{Permission, Role},
+    security_log::{self, AuthFailureReason},
+    session::{self, Session},
+};
 
-use crate::env_vars::BackendVars;
+/// The cookie the token is kept in when ``AUTH_COOKIES`` is enabled.
+const SESSION_COOKIE: &str = "session";
 
 #[derive(Serialize)]
 #[serde(transparent)]
-pub(crate) struct AdminToken(String);
+pub(crate) struct SessionToken(String);
 
-impl AdminToken {
+impl SessionToken {
     pub fn new(token: String) -> Self {
         Self(token)
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
-/// Verifies the token is that of the admin account.
-pub(crate) fn has_admin_token(req: &HttpRequest, env_var: &BackendVars) -> bool {
-    let auth_header = req.headers().get(header::AUTHORIZATION);
+/// What kind of token ``login`` issues, set by ``TOKEN_MODE``.
+#[derive(Clone)]
+pub(crate) enum TokenMode {
+    /// Random tokens of sessions stored in the SQLite DB, which can be revoked.
+    Session,
+    /// Signed JWTs verified without a DB lookup, so replicas don't need a shared session store.
+    Jwt(JwtKeys),
+}
 
-    auth_header
+impl TokenMode {
+    /// Parses ``TOKEN_MODE``, which is ``session`` (default) or ``jwt``.
+    pub fn from_vars(vars: &BackendVars) -> io::Result<Self> {
+        match vars.token_mode.as_deref() {
+            None | Some("session") => Ok(TokenMode::Session),
+            Some("jwt") => Ok(TokenMode::Jwt(JwtKeys::from_vars(vars)?)),
+            Some(mode) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Bad TOKEN_MODE {mode:?}. Expected session or jwt"),
+            )),
+        }
+    }
+
+    /// Issues a token for the user whose credentials were just checked.
+    pub async fn issue(
+        &self,
+        pool: &SqlitePool,
+        username: &str,
+        role: Role,
+    ) -> Result<SessionToken, Box<dyn Error>> {
+        match self {
+            TokenMode::Session => Ok(session::create_session(pool, username).await?),
+            TokenMode::Jwt(keys) => Ok(keys.issue(username, role)?),
+        }
+    }
+}
+
+/// Gets the token from the Authorization header if it uses the ``Bearer`` type.
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
         .and_then(|auth| auth.to_str().ok())
-        .filter(|auth_str| {
-            auth_str.starts_with("Bearer ")
-                && auth_str.get(7..) == Some(env_var.admin_token.as_str())
-        })
-        .is_some()
+        .and_then(|auth_str| auth_str.strip_prefix("Bearer "))
 }
 
-#[macro_export]
-macro_rules! verify_admin_token {
-    ($req:ident, $vars:ident) => {
-        use actix_web::HttpResponse;
-        use $crate::token::has_admin_token;
+/// Whether ``login`` sets the token as a cookie and the cookie is accepted in place of the Authorization header.
+pub(crate) fn auth_cookies_enabled(req: &HttpRequest) -> bool {
+    req.app_data::<BackendVars>()
+        .and_then(|vars| vars.auth_cookies)
+        .unwrap_or(false)
+}
+
+/// The cookie holding the token. It can't be read by JS and is never sent cross-site.
+pub(crate) fn session_cookie(token: &SessionToken) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token.as_str().to_string())
+        .path("/api")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+/// Gets the token from the Authorization header, or from the session cookie if ``AUTH_COOKIES`` is enabled and there's
+/// no Authorization header.
+pub(crate) fn request_token(req: &HttpRequest) -> Option<String> {
+    match bearer_token(req) {
+        Some(token) => Some(token.to_string()),
+        None if auth_cookies_enabled(req) => req
+            .cookie(SESSION_COOKIE)
+            .map(|cookie| cookie.value().to_string()),
+        None => None,
+    }
+}
+
+/// Gets the live session or valid JWT the request's token belongs to, logging a security event if there isn't one.
+pub(crate) async fn authenticated_session(req: &HttpRequest) -> Option<Session> {
+    let (pool, token_mode) = match (req.app_data::<SqlitePool>(), req.app_data::<TokenMode>()) {
+        (Some(pool), Some(token_mode)) => (pool, token_mode),
+        _ => {
+            error!(
+                "{MISSING_APP_DATA}. SQLite Connection Pool: {:?}; Token mode set: {}",
+                req.app_data::<SqlitePool>(),
+                req.app_data::<TokenMode>().is_some(),
+            );
+
+            return None;
+        }
+    };
+    let peer_addr = listener::client_addr(req);
+    let token = match request_token(req) {
+        Some(token) => token,
+        None => {
+            security_log::auth_failure(peer_addr.as_deref(), None, AuthFailureReason::MissingToken);
+
+            return None;
+        }
+    };
+    let found = match token_mode {
+        TokenMode::Session => session::find_session(pool, &token).await,
+        TokenMode::Jwt(keys) => match keys.verify(&token) {
+            Ok(claims) => Ok(Some(Session {
+                id: None,
+                username: claims.sub,
+                role: claims.role,
+            })),
+            Err(err) => {
+                debug!("Rejected JWT: {err}");
 
-        if !has_admin_token(&$req, &$vars) {
-            return HttpResponse::Unauthorized().finish();
+                Ok(None)
+            }
+        },
+    };
+
+    match found {
+        Ok(Some(session)) => Some(session),
+        Ok(None) => {
+            security_log::auth_failure(peer_addr.as_deref(), None, AuthFailureReason::InvalidToken);
+
+            None
+        }
+        Err(err) => {
+            error!("Encountered sqlx error while looking up session: {err}");
+
+            None
+        }
+    }
+}
+
+/// Whether the request's client certificate belongs to the session's user, if the permission needs one.
+async fn has_client_cert(req: &HttpRequest, session: &Session, permission: Permission) -> bool {
+    let required = permission.requires_client_cert()
+        && req
+            .app_data::<BackendVars>()
+            .is_none_or(client_cert::client_certs_required);
+
+    if !required {
+        return true;
+    }
+
+    match client_cert::certificate_user(req).await {
+        Some(username) if username == session.username => true,
+        _ => {
+            security_log::auth_failure(
+                listener::client_addr(req).as_deref(),
+                Some(&session.username),
+                AuthFailureReason::BadClientCertificate,
+            );
+
+            false
+        }
+    }
+}
+
+/// Verifies the token belongs to a live session whose user's role grants the permission. With
+/// ``REQUIRE_CLIENT_CERTS``, some permissions also need a client certificate of the same user.
+pub(crate) async fn has_permission(req: &HttpRequest, permission: Permission) -> bool {
+    match authenticated_session(req).await {
+        Some(session) if session.role.has_permission(permission) => {
+            if !has_client_cert(req, &session, permission).await {
+                return false;
+            }
+
+            info!(
+                "Session {:?} of {} accessed {} {}",
+                session.id,
+                session.username,
+                req.method(),
+                req.path(),
+            );
+
+            true
+        }
+        Some(session) => {
+            security_log::auth_failure(
+                listener::client_addr(req).as_deref(),
+                Some(&session.username),
+                AuthFailureReason::InsufficientPermission,
+            );
+
+            false
+        }
+        None => false,
+    }
+}
+
+#[macro_export]
+macro_rules! verify_permission {
+    ($req:ident, $permission:expr) => {
+        if !$crate::token::has_permission(&$req, $permission).await {
+            return actix_web::HttpResponse::Unauthorized().finish();
         }
     };
 }
@@ -0,0 +1,163 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha1::Sha1;
+use subtle::ConstantTimeEq;
+
+/// RFC 6238 parameters every common authenticator app supports.
+const STEP_SECS: u64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_BYTES: usize = 20;
+/// How many steps before or after the current one a code is still accepted for, to allow for clock drift.
+const ALLOWED_SKEW: u64 = 1;
+const ISSUER: &str = "GreenSite";
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Generates a new random TOTP secret.
+pub(crate) fn generate_secret() -> Vec<u8> {
+    rand::thread_rng().gen::<[u8; SECRET_BYTES]>().to_vec()
+}
+
+/// Encodes the bytes as unpadded RFC 4648 base32, which is how authenticator apps expect secrets.
+fn to_base32(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31] as char);
+        }
+
+        buffer &= (1 << bits) - 1;
+    }
+
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+
+    encoded
+}
+
+/// The base32 secret and ``otpauth://`` URI to enroll the user's authenticator app with.
+pub(crate) fn provisioning(username: &str, secret: &[u8]) -> (String, String) {
+    let secret = to_base32(secret);
+    let uri = format!(
+        "otpauth://totp/{ISSUER}:{username}?secret={secret}&issuer={ISSUER}\
+         &algorithm=SHA1&digits={DIGITS}&period={STEP_SECS}"
+    );
+
+    (secret, uri)
+}
+
+/// The code for the time step, per RFC 4226's dynamic truncation.
+fn code_for_step(secret: &[u8], step: u64) -> u32 {
+    let mut mac = HmacSha1::new_from_slice(secret).expect("HMAC accepts keys of any length");
+
+    mac.update(&step.to_be_bytes());
+
+    let hash = mac.finalize().into_bytes();
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    binary % 10u32.pow(DIGITS)
+}
+
+/// Verifies the code at the Unix time, allowing for clock drift. Codes for steps up to and including
+/// ``last_step`` are rejected so they can't be replayed. Returns the matching step to store as the new ``last_step``.
+pub(crate) fn verify_at(secret: &[u8], code: &str, unix_time: u64, last_step: u64) -> Option<u64> {
+    if code.len() != DIGITS as usize || !code.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let current_step = unix_time / STEP_SECS;
+    let first_step = current_step.saturating_sub(ALLOWED_SKEW).max(last_step + 1);
+
+    (first_step..=current_step + ALLOWED_SKEW).find(|&step| {
+        let expected = format!(
+            "{:0width$}",
+            code_for_step(secret, step),
+            width = DIGITS as usize
+        );
+
+        bool::from(expected.as_bytes().ct_eq(code.as_bytes()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The SHA-1 secret of the RFC 6238 test vectors.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[test]
+    fn verifies_rfc_6238_vectors() {
+        assert_eq!(verify_at(RFC_SECRET, "287082", 59, 0), Some(1));
+        assert_eq!(
+            verify_at(RFC_SECRET, "081804", 1111111109, 0),
+            Some(37037036)
+        );
+        assert_eq!(
+            verify_at(RFC_SECRET, "050471", 1111111111, 0),
+            Some(37037037)
+        );
+        assert_eq!(
+            verify_at(RFC_SECRET, "005924", 1234567890, 0),
+            Some(41152263)
+        );
+    }
+
+    #[test]
+    fn accepts_codes_within_skew() {
+        // The code for step 1 is still accepted during step 2 but not step 3.
+        assert_eq!(verify_at(RFC_SECRET, "287082", 89, 0), Some(1));
+        assert_eq!(verify_at(RFC_SECRET, "287082", 119, 0), None);
+        // It's also accepted a step early.
+        assert_eq!(
+            verify_at(RFC_SECRET, "081804", 1111111079, 0),
+            Some(37037036)
+        );
+        assert_eq!(verify_at(RFC_SECRET, "081804", 1111111049, 0), None);
+    }
+
+    #[test]
+    fn rejects_replayed_codes() {
+        assert_eq!(verify_at(RFC_SECRET, "287082", 59, 1), None);
+        assert_eq!(verify_at(RFC_SECRET, "081804", 1111111109, 37037036), None);
+        assert_eq!(
+            verify_at(RFC_SECRET, "050471", 1111111111, 37037036),
+            Some(37037037)
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_codes() {
+        assert_eq!(verify_at(RFC_SECRET, "28708", 59, 0), None);
+        assert_eq!(verify_at(RFC_SECRET, "2870822", 59, 0), None);
+        assert_eq!(verify_at(RFC_SECRET, "28708a", 59, 0), None);
+        assert_eq!(verify_at(RFC_SECRET, "", 59, 0), None);
+    }
+
+    #[test]
+    fn encodes_base32() {
+        assert_eq!(to_base32(b""), "");
+        assert_eq!(to_base32(b"f"), "MY");
+        assert_eq!(to_base32(b"fo"), "MZXQ");
+        assert_eq!(to_base32(b"foo"), "MZXW6");
+        assert_eq!(to_base32(b"foob"), "MZXW6YQ");
+        assert_eq!(to_base32(b"fooba"), "MZXW6YTB");
+        assert_eq!(to_base32(b"foobar"), "MZXW6YTBOI");
+        assert_eq!(to_base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+}
@@ -0,0 +1,220 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use sqlx::{FromRow, SqlitePool};
+
+use crate::{role::Role, totp};
+
+/// A user as listed to admins. The password hash is never included.
+#[derive(Debug, FromRow, Serialize)]
+pub(crate) struct User {
+    pub username: String,
+    pub role: Role,
+    pub disabled: bool,
+}
+
+/// Only matches a user whose removal still leaves an enabled admin, so there's always someone who can manage users.
+/// Checked in the same statement as the change so concurrent requests can't both remove an admin.
+const KEEPS_AN_ADMIN: &str = "(NOT (role='admin' AND NOT disabled) \
+    OR (SELECT COUNT(*) FROM users WHERE role='admin' AND NOT disabled) > 1)";
+
+/// Lists every user, sorted by username.
+pub(crate) async fn list_users(pool: &SqlitePool) -> sqlx::Result<Vec<User>> {
+    sqlx::query_as("SELECT username, role, disabled FROM users ORDER BY username;")
+        .fetch_all(pool)
+        .await
+}
+
+/// Gets the stored password of the user if they exist and aren't disabled.
+pub(crate) async fn stored_password(
+    pool: &SqlitePool,
+    username: &str,
+) -> sqlx::Result<Option<String>> {
+    let stored: Option<(String,)> =
+        sqlx::query_as("SELECT password FROM users WHERE username=? AND NOT disabled;")
+            .bind(username)
+            .fetch_optional(pool)
+            .await?;
+
+    Ok(stored.map(|(password,)| password))
+}
+
+/// Gets the role of the user and whether they have TOTP enabled if they exist and aren't disabled.
+pub(crate) async fn login_account(
+    pool: &SqlitePool,
+    username: &str,
+) -> sqlx::Result<Option<(Role, bool)>> {
+    sqlx::query_as("SELECT role, totp_enabled FROM users WHERE username=? AND NOT disabled;")
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+}
+
+/// Whether the user exists, disabled or not.
+pub(crate) async fn user_exists(pool: &SqlitePool, username: &str) -> sqlx::Result<bool> {
+    let exists: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM users WHERE username=?;")
+        .bind(username)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(exists.is_some())
+}
+
+/// Creates a user with an already hashed password. Returns false if the username is taken.
+pub(crate) async fn create_user(
+    pool: &SqlitePool,
+    username: &str,
+    password_hash: &str,
+    role: Role,
+) -> sqlx::Result<bool> {
+    let result =
+        sqlx::query("INSERT OR IGNORE INTO users (username, password, role) VALUES (?, ?, ?);")
+            .bind(username)
+            .bind(password_hash)
+            .bind(role)
+            .execute(pool)
+            .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Replaces the user's password with an already hashed one. Returns false if the user doesn't exist.
+pub(crate) async fn set_password(
+    pool: &SqlitePool,
+    username: &str,
+    password_hash: &str,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query("UPDATE users SET password=? WHERE username=?;")
+        .bind(password_hash)
+        .bind(username)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Changes the user's role. Returns false if the user doesn't exist or is the last enabled admin being demoted.
+pub(crate) async fn set_role(pool: &SqlitePool, username: &str, role: Role) -> sqlx::Result<bool> {
+    let query =
+        format!("UPDATE users SET role=? WHERE username=? AND (?='admin' OR {KEEPS_AN_ADMIN});");
+    let result = sqlx::query(&query)
+        .bind(role)
+        .bind(username)
+        .bind(role)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Disables or re-enables the user. Returns false if the user doesn't exist or is the last enabled admin being
+/// disabled.
+pub(crate) async fn set_disabled(
+    pool: &SqlitePool,
+    username: &str,
+    disabled: bool,
+) -> sqlx::Result<bool> {
+    let query =
+        format!("UPDATE users SET disabled=? WHERE username=? AND (NOT ? OR {KEEPS_AN_ADMIN});");
+    let result = sqlx::query(&query)
+        .bind(disabled)
+        .bind(username)
+        .bind(disabled)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Deletes the user. Returns false if the user doesn't exist or is the last enabled admin.
+pub(crate) async fn delete_user(pool: &SqlitePool, username: &str) -> sqlx::Result<bool> {
+    let query = format!("DELETE FROM users WHERE username=? AND {KEEPS_AN_ADMIN};");
+    let result = sqlx::query(&query).bind(username).execute(pool).await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// The TOTP enrollment of a user. ``secret`` is set once enrollment starts and ``enabled`` once it's confirmed.
+#[derive(Debug, FromRow)]
+pub(crate) struct Totp {
+    #[sqlx(rename = "totp_secret")]
+    pub secret: Option<Vec<u8>>,
+    #[sqlx(rename = "totp_enabled")]
+    pub enabled: bool,
+    #[sqlx(rename = "totp_last_step")]
+    pub last_step: i64,
+}
+
+/// Gets the TOTP enrollment of the user if they exist and aren't disabled.
+pub(crate) async fn totp(pool: &SqlitePool, username: &str) -> sqlx::Result<Option<Totp>> {
+    sqlx::query_as(
+        "SELECT totp_secret, totp_enabled, totp_last_step FROM users \
+         WHERE username=? AND NOT disabled;",
+    )
+    .bind(username)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Starts TOTP enrollment with a new secret, or clears the secret and disables TOTP if ``secret`` is ``None``.
+pub(crate) async fn set_totp_secret(
+    pool: &SqlitePool,
+    username: &str,
+    secret: Option<&[u8]>,
+) -> sqlx::Result<()> {
+    sqlx::query(
+        "UPDATE users SET totp_secret=?, totp_enabled=FALSE, totp_last_step=0 WHERE username=?;",
+    )
+    .bind(secret)
+    .bind(username)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Records the step a TOTP code was accepted for, enabling TOTP if enrollment was pending. Returns false if
+/// another request already accepted a code for that step or a later one.
+pub(crate) async fn accept_totp_step(
+    pool: &SqlitePool,
+    username: &str,
+    step: i64,
+) -> sqlx::Result<bool> {
+    let result = sqlx::query(
+        "UPDATE users SET totp_enabled=TRUE, totp_last_step=? \
+         WHERE username=? AND totp_last_step < ?;",
+    )
+    .bind(step)
+    .bind(username)
+    .bind(step)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Verifies a TOTP code of the user against their secret, whether or not enrollment was confirmed yet. An accepted
+/// code can't be used again.
+pub(crate) async fn verify_totp(
+    pool: &SqlitePool,
+    username: &str,
+    code: &str,
+) -> sqlx::Result<bool> {
+    let (secret, last_step) = match totp(pool, username).await? {
+        Some(Totp {
+            secret: Some(secret),
+            last_step,
+            ..
+        }) => (secret, last_step),
+        _ => return Ok(false),
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    match totp::verify_at(&secret, code, now, last_step as u64) {
+        Some(step) => accept_totp_step(pool, username, step as i64).await,
+        None => Ok(false),
+    }
+}